@@ -14,45 +14,69 @@ extern crate serde_json;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
-use client_lib::{Bot, Telegram};
-use client_lib::entities::Request;
+use client_lib::{Bot, Telegram, IGNORE_METHOD};
+use client_lib::entities::{Message, Request};
+use client_lib::generator::{Generator, GENERATOR_SECTION};
+use client_lib::router::{Args, Router};
+use client_lib::session::Session;
 
 use serde_json::value::Value as JsonValue;
 
 use toml::Value as TomlValue;
 
+/// generator command building swears, also used by swearto and to answer other messages
+const SWEAR_COMMAND: &'static str = "swear";
+
 struct BlasphemyBot {
-    _api: Telegram,
-    _config: Arc<RwLock<TomlValue>>,
-    _session: Arc<RwLock<HashMap<String, JsonValue>>>,
+    api: Telegram,
+    config: Arc<RwLock<TomlValue>>,
+    session: Session,
+    router: Router<BlasphemyBot>,
 }
 
 impl Bot for BlasphemyBot {
     fn new(api: Telegram, config: &Arc<RwLock<TomlValue>>, session: &Arc<RwLock<HashMap<String, JsonValue>>>) -> BlasphemyBot {
         BlasphemyBot {
-            _api: api,
-            _config: config.clone(),
-            _session: session.clone(),
+            api: api,
+            config: config.clone(),
+            session: Session::new(session),
+            //swear and blackhumor are generator commands, configured in bot's toml
+            router: Router::from_config(config)
+                .command("about", "", "about this bot", |bot: &BlasphemyBot, _args, request| bot.about(request))
+                .command("help", "", "shows this help", |bot: &BlasphemyBot, _args, request| bot.help(request))
+                .command("swearto", "<name>", "swears to someone", |bot: &BlasphemyBot, args, request| bot.swearto(request, args))
+                .generators(config, session),
         }
     }
 
-    fn parse_message(&self, _request: &Request) -> Result<(String, Vec<String>), String> {
-//         if request.message.text.is_none() {
-//             return Err(String::from("Command not found"));
-//         }
+    fn get_api(&self) -> Option<&Telegram> {
+        Some(&self.api)
+    }
+
+    fn parse_message(&self, request: &Request) -> Result<(String, Vec<String>), String> {
+        let msg = match request.get_message() {
+            &Some(ref msg) => msg,
+            &None => { return Err(String::from("Empty message")); },
+        };
+        match self.router.parse_message(msg) {
+            Some((ref method, _)) if method == "start" => { return Ok((String::from("help"), Vec::new())); },
+            Some((method, args)) => if self.router.has_command(&method) {
+                return Ok((method, args));
+            },
+            None => {},
+        }
 
-        Ok((String::from("swear"), Vec::new()))
+        //anything else is answered with a swear, in groups only when the bot is mentioned
+        if msg.get_chat().get_type() == "private" || self.is_mentioned(msg)? {
+            Ok((String::from(SWEAR_COMMAND), Vec::new()))
+        }
+        else {
+            Ok((String::from(IGNORE_METHOD), Vec::new()))
+        }
     }
 
     fn dispatch(&self, method: &str, args: Vec<String>, request: &Request) -> Result<JsonValue, String> {
-        match method {
-            "about" => self.about(request),
-            "help" => self.help(request),
-            "swear" => self.swear(request),
-            "swearto" => self.swearto(request, args),
-            "blackhumor" => self.blackhumor(request),
-            _ => Err(format!("Method {} not found", method)),
-        }
+        self.router.dispatch(self, method, args, request)
     }
 }
 
@@ -61,20 +85,47 @@ impl BlasphemyBot {
         Err(String::from("about command"))
     }
 
-    fn help(&self, _request: &Request) -> Result<JsonValue, String> {
-        Err(String::from("help command"))
+    fn help(&self, request: &Request) -> Result<JsonValue, String> {
+        match request.get_message() {
+            &Some(ref msg) => self.api.send_message(&msg.get_chat().get_id().to_string(), &self.router.help_text(), None, None, None, None),
+            &None => Err(String::from("Empty message")),
+        }
     }
 
-    fn swear(&self, _request: &Request) -> Result<JsonValue, String> {
-        Err(String::from("swear command"))
+    /// swears to the given name, or to the author of the replied message
+    fn swearto(&self, request: &Request, args: &Args) -> Result<JsonValue, String> {
+        let msg = BlasphemyBot::get_message(request)?;
+        let (name, reply_id) = match (args.is_empty(), msg.get_reply_to_message()) {
+            (false, _) => (args.rest(0), None),
+            (true, &Some(ref reply)) => (match reply.get_from().get_username() {
+                &Some(ref username) => format!("@{}", username),
+                &None => reply.get_from().get_first_name().to_owned(),
+            }, Some(reply.get_message_id().to_string())),
+            (true, &None) => { return Err(String::from("Missing argument name")); },
+        };
+        let generator = {
+            let config = self.config.read().map_err(|e| format!("Error read locking config: {:?}", e))?;
+            Generator::from_config(&config)?.ok_or(format!("{} section not found", GENERATOR_SECTION))?
+        };
+        let chat_id = msg.get_chat().get_id();
+        let swear = generator.generate(SWEAR_COMMAND, "", msg.get_from().get_first_name(), Some(&self.session.chat(chat_id)))?;
+        self.api.send_message(&chat_id.to_string(), &format!("{}, {}", name, swear), reply_id.as_ref().map(String::as_str), None, None, None)
     }
 
-    fn swearto(&self, _request: &Request, _args: Vec<String>) -> Result<JsonValue, String> {
-        Err(String::from("swearto command"))
+    fn get_message(request: &Request) -> Result<&Message, String> {
+        match request.get_message() {
+            &Some(ref msg) => Ok(msg),
+            &None => Err(String::from("Empty message")),
+        }
     }
 
-    fn blackhumor(&self, _request: &Request) -> Result<JsonValue, String> {
-        Err(String::from("blackhumor command"))
+    /// checks if the message mentions BOT_NAME
+    fn is_mentioned(&self, msg: &Message) -> Result<bool, String> {
+        let config = self.config.read().map_err(|e| format!("Error read locking config: {:?}", e))?;
+        Ok(match config.get("BOT_NAME").and_then(|value| value.as_str()) {
+            Some(name) => msg.get_mentions().iter().any(|mention| mention.trim_start_matches('@').eq_ignore_ascii_case(name.trim_start_matches('@'))),
+            None => false,
+        })
     }
 }
 
@@ -104,37 +155,103 @@ pub extern fn init_bot(ptr_config: *const Arc<RwLock<TomlValue>>, ptr_session: *
 mod tests {
     use super::{toml, serde_json, init_bot};
     use super::client_lib::entities::Request;
+    use super::client_lib::mock::{MockServer, json_ok};
+    use super::serde_json::value::Value as JsonValue;
     use std::collections::HashMap;
     use std::sync::{Arc, RwLock};
 
     #[test]
     fn it_works() {
-        let config: toml::Value = toml::from_str(r#"SECRET = "prova"
-HTTP_TOKEN = "test""#).unwrap();
+        let server = MockServer::start().unwrap();
+        let config: toml::Value = toml::from_str(&format!(r#"SECRET = "prova"
+HTTP_TOKEN = "test"
+API_URL = "{}"
+BOT_NAME = "BlasphemyBot"
+
+[GENERATOR]
+SEED = 42
+
+[GENERATOR.COMMANDS.swear]
+DESCRIPTION = "swears"
+TEMPLATE = "{{WordsA}}{{WordsB}}{{WordsC}}"
+
+[GENERATOR.COMMANDS.blackhumor]
+DESCRIPTION = "black humor"
+TEMPLATE = "{{BlacHumor}}"
+
+[WordsA]
+0 = "Holy "
+1 = "Jumping "
+2 = "Great "
+
+[WordsB]
+0 = "Christ"
+1 = "Zeus"
+2 = "Allah"
+
+[WordsC]
+1 = " almighty"
+2 = " on a bike"
+10 = " on toast"
+
+[BlacHumor]
+0 = "joke zero"
+1 = "joke one""#, server.get_url())).unwrap();
+        let config = Arc::new(RwLock::new(config));
         let session = Arc::new(RwLock::new(HashMap::new()));
-        let request: Request = serde_json::from_str(r#"{
+        let update = |chat_type: &str, text: &str, entities: &str| serde_json::from_str::<Request>(&format!(r#"{{
 "update_id":10000,
-"message":{
+"message":{{
   "date":1441645532,
-  "chat":{
+  "chat":{{
      "last_name":"Test Lastname",
      "id":1111111,
-     "type": "private",
+     "type": "{}",
      "first_name":"Test Firstname",
      "username":"Testusername"
-  },
+  }},
   "message_id":1365,
-  "from":{
+  "from":{{
     "is_bot": true,
      "last_name":"Test Lastname",
      "id":1111111,
      "first_name":"Test Firstname",
      "username":"Testusername"
-  },
-  "text":"/start"
-}
-}"#).unwrap();
+  }},
+  "text":"{}",
+  "entities":[{}]
+}}
+}}"#, chat_type, text, entities)).unwrap();
+        let command = |text: &str| update("private", text, &format!(r#"{{"type":"bot_command","offset":0,"length":{}}}"#, text.find(' ').unwrap_or(text.len())));
+        let run = |request: &Request| unsafe { &*init_bot(Box::into_raw(Box::new(config.clone())), Box::into_raw(Box::new(session.clone())), "prova", Box::into_raw(Box::new(request))) };
+        let help = "/about - about this bot\n/help - shows this help\n/swearto <name> - swears to someone\n/blackhumor - black humor\n/swear - swears";
+
+        assert_eq!(run(&command("/start")), &Ok(json_ok()));
+        assert!(server.was_called("sendMessage", &[("chat_id", "1111111"), ("text", help)]));
+
+        //seeded, so words are always the same
+        server.clear();
+        assert_eq!(run(&command("/swear")), &Ok(json_ok()));
+        assert!(server.was_called("sendMessage", &[("chat_id", "1111111"), ("text", "Great Allah on a bike")]));
+        assert_eq!(run(&command("/swearto @pippo")), &Ok(json_ok()));
+        assert!(server.was_called("sendMessage", &[("chat_id", "1111111"), ("text", "@pippo, Great Allah on a bike")]));
+        assert_eq!(run(&command("/swearto")), &Err(String::from("Missing argument name")));
+        assert_eq!(run(&command("/blackhumor")), &Ok(json_ok()));
+        assert!(server.was_called("sendMessage", &[("chat_id", "1111111"), ("text", "joke zero")]));
+
+        //commands addressed to other bots, and other messages, get a swear in private chats
+        server.clear();
+        assert_eq!(run(&command("/help@OtherBot")), &Ok(json_ok()));
+        assert_eq!(run(&update("private", "hello", "")), &Ok(json_ok()));
+        assert_eq!(server.get_calls_to("sendMessage").iter().filter(|call| call.get_text("text") == Some(String::from("Great Allah on a bike"))).count(), 2);
+
+        //only when mentioned in groups
+        server.clear();
+        assert_eq!(run(&update("group", "hello", "")), &Ok(JsonValue::Null));
+        assert_eq!(run(&update("group", "hello @blasphemybot", r#"{"type":"mention","offset":6,"length":13}"#)), &Ok(json_ok()));
+        assert_eq!(server.get_calls_to("sendMessage").len(), 1);
 
-        assert_eq!(init_bot(Box::into_raw(Box::new(Arc::new(RwLock::new(config)))), Box::into_raw(Box::new(session)), "prova", Box::into_raw(Box::new(request))), Err(String::from("TODO")));
+        assert_eq!(run(&command("/help@blasphemybot")), &Ok(json_ok()));
+        assert!(server.was_called("sendMessage", &[("chat_id", "1111111"), ("text", help)]));
     }
 }
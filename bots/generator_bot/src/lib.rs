@@ -0,0 +1,161 @@
+#![deny(warnings)]
+#![deny(missing_docs)]
+
+//! # generator_bot
+//!
+//! Telegram bot GeneratorBot
+//!
+//! Template bot replying to commands with text generated from its toml, see client_lib::generator
+
+extern crate client_lib;
+extern crate toml;
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use client_lib::{Bot, Telegram, IGNORE_METHOD};
+use client_lib::entities::Request;
+use client_lib::router::Router;
+
+use serde_json::value::Value as JsonValue;
+
+use toml::Value as TomlValue;
+
+struct GeneratorBot {
+    api: Telegram,
+    router: Router<GeneratorBot>,
+}
+
+impl Bot for GeneratorBot {
+    fn new(api: Telegram, config: &Arc<RwLock<TomlValue>>, session: &Arc<RwLock<HashMap<String, JsonValue>>>) -> GeneratorBot {
+        GeneratorBot {
+            api: api,
+            router: Router::from_config(config)
+                .command("help", "", "shows this help", |bot: &GeneratorBot, _args, request| bot.help(request))
+                .generators(config, session),
+        }
+    }
+
+    fn get_api(&self) -> Option<&Telegram> {
+        Some(&self.api)
+    }
+
+    fn parse_message(&self, request: &Request) -> Result<(String, Vec<String>), String> {
+        let msg = match request.get_message() {
+            &Some(ref msg) => msg,
+            &None => { return Err(String::from("Empty message")); },
+        };
+        match self.router.parse_message(msg) {
+            Some((ref method, _)) if method == "start" => Ok((String::from("help"), Vec::new())),
+            Some((method, args)) => if self.router.has_command(&method) {
+                Ok((method, args))
+            }
+            else {
+                //likely meant for another bot, in groups
+                Ok((String::from(IGNORE_METHOD), Vec::new()))
+            },
+            //plain messages aren't answered
+            None => Ok((String::from(IGNORE_METHOD), Vec::new())),
+        }
+    }
+
+    fn dispatch(&self, method: &str, args: Vec<String>, request: &Request) -> Result<JsonValue, String> {
+        self.router.dispatch(self, method, args, request)
+    }
+}
+
+impl GeneratorBot {
+    fn help(&self, request: &Request) -> Result<JsonValue, String> {
+        match request.get_message() {
+            &Some(ref msg) => self.api.send_message(&msg.get_chat().get_id().to_string(), &self.router.help_text(), None, None, None, None),
+            &None => Err(String::from("Empty message")),
+        }
+    }
+}
+
+/// public C ABI to call the bot
+#[no_mangle]
+pub extern fn init_bot(ptr_config: *const Arc<RwLock<TomlValue>>, ptr_session: *const Arc<RwLock<HashMap<String, JsonValue>>>, secret: &str, ptr_request: *const &Request) -> *const Result<JsonValue, String> {
+    let config = unsafe {
+        assert!(!ptr_config.is_null());
+        &*ptr_config
+    };
+    let session = unsafe {
+        assert!(!ptr_session.is_null());
+        &*ptr_session
+    };
+    let request = unsafe {
+        assert!(!ptr_request.is_null());
+        &*ptr_request
+    };
+
+    Box::into_raw(Box::new(match Telegram::init_bot(GeneratorBot::new, secret, &config, &session) {
+        Ok(bot) => bot.parse(request),
+        Err(e) => Err(format!("Error during bot init: {}", e)),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{toml, serde_json, init_bot};
+    use super::client_lib::entities::Request;
+    use super::client_lib::mock::{MockServer, json_ok};
+    use super::serde_json::value::Value as JsonValue;
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+
+    #[test]
+    fn it_works() {
+        let server = MockServer::start().unwrap();
+        let config: toml::Value = toml::from_str(&format!(r#"SECRET = "prova"
+HTTP_TOKEN = "test"
+API_URL = "{}"
+BOT_NAME = "GeneratorBot"
+
+[GENERATOR]
+SEED = 42
+
+[GENERATOR.COMMANDS.greet]
+USAGE = "<name>"
+DESCRIPTION = "greets someone"
+TEMPLATE = "{{Greeting}} {{args}}, from {{user}}"
+
+[Greeting]
+0 = "Hello""#, server.get_url())).unwrap();
+        let config = Arc::new(RwLock::new(config));
+        let session = Arc::new(RwLock::new(HashMap::new()));
+        let update = |text: &str| serde_json::from_str::<Request>(&format!(r#"{{
+"update_id":10000,
+"message":{{
+  "date":1441645532,
+  "chat":{{
+     "id":1111111,
+     "type": "private",
+     "first_name":"Test Firstname"
+  }},
+  "message_id":1365,
+  "from":{{
+    "is_bot": false,
+     "id":1111111,
+     "first_name":"Test Firstname"
+  }},
+  "text":"{}",
+  "entities":[{{"type":"bot_command","offset":0,"length":{}}}]
+}}
+}}"#, text, text.find(' ').unwrap_or(text.len()))).unwrap();
+        let run = |request: &Request| unsafe { &*init_bot(Box::into_raw(Box::new(config.clone())), Box::into_raw(Box::new(session.clone())), "prova", Box::into_raw(Box::new(request))) };
+
+        assert_eq!(run(&update("/start")), &Ok(json_ok()));
+        assert!(server.was_called("sendMessage", &[("chat_id", "1111111"), ("text", "/help - shows this help\n/greet <name> - greets someone")]));
+
+        assert_eq!(run(&update("/greet@GeneratorBot pippo")), &Ok(json_ok()));
+        assert!(server.was_called("sendMessage", &[("chat_id", "1111111"), ("text", "Hello pippo, from Test Firstname")]));
+
+        //messages that aren't commands for this bot are ignored, not failed
+        server.clear();
+        assert_eq!(run(&update("/missing")), &Ok(JsonValue::Null));
+        assert_eq!(run(&update("/greet@OtherBot pippo")), &Ok(JsonValue::Null));
+        assert!(server.get_calls_to("sendMessage").is_empty());
+    }
+}
@@ -0,0 +1,181 @@
+use std::fs;
+use std::path::Path;
+
+use serde_json;
+use serde_json::value::Value as JsonValue;
+
+use client_lib::Telegram;
+use client_lib::entities::{InputFile, ParseMode, ReplyMarkup};
+
+/// something a json protocol command asks NoFlyBot to do
+/// chat_id defaults to the chat the command was sent in
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// sends a text message
+    SendMessage {
+        #[serde(default)]
+        chat_id: Option<i64>,
+        text: String,
+        #[serde(default)]
+        parse_mode: Option<String>,
+        #[serde(default)]
+        reply_to: Option<u64>,
+        #[serde(default)]
+        reply_markup: Option<ReplyMarkup>,
+    },
+    /// sends a photo, by URL, file_id or absolute local path inside the command's files_dir
+    SendPhoto {
+        #[serde(default)]
+        chat_id: Option<i64>,
+        photo: String,
+        #[serde(default)]
+        caption: Option<String>,
+        #[serde(default)]
+        reply_to: Option<u64>,
+        #[serde(default)]
+        reply_markup: Option<ReplyMarkup>,
+    },
+    /// sends a point on the map
+    SendLocation {
+        #[serde(default)]
+        chat_id: Option<i64>,
+        latitude: f64,
+        longitude: f64,
+        #[serde(default)]
+        live_period: Option<u32>,
+        #[serde(default)]
+        reply_to: Option<u64>,
+        #[serde(default)]
+        reply_markup: Option<ReplyMarkup>,
+    },
+    /// edits the text of a message sent by the bot
+    Edit {
+        #[serde(default)]
+        chat_id: Option<i64>,
+        message_id: u64,
+        text: String,
+        #[serde(default)]
+        parse_mode: Option<String>,
+        #[serde(default)]
+        reply_markup: Option<ReplyMarkup>,
+    },
+    /// moves a live location sent by the bot
+    EditLiveLocation {
+        #[serde(default)]
+        chat_id: Option<i64>,
+        message_id: u64,
+        latitude: f64,
+        longitude: f64,
+    },
+    /// stops updating a live location sent by the bot
+    StopLiveLocation {
+        #[serde(default)]
+        chat_id: Option<i64>,
+        message_id: u64,
+    },
+    /// deletes a message
+    Delete {
+        #[serde(default)]
+        chat_id: Option<i64>,
+        message_id: u64,
+    },
+}
+
+/// parses a command output, a json list of actions
+pub fn parse(output: &str) -> Result<Vec<Action>, String> {
+    serde_json::from_str(output).map_err(|e| format!("Invalid actions {}: {}", output.trim(), e))
+}
+
+/// performs every action in order, stopping on the first failure, calls refused by Telegram included
+/// returns every call result, the failed one last: once an action took effect the update must not fail,
+/// or Telegram would deliver it again and the actions would be repeated
+pub fn execute(api: &Telegram, chat_id: i64, files_dir: Option<&Path>, actions: Vec<Action>) -> Result<JsonValue, String> {
+    let mut results = Vec::new();
+    for action in actions {
+        match perform(api, chat_id, files_dir, action) {
+            Ok(res) => if res["ok"].as_bool() == Some(true) {
+                results.push(res);
+            }
+            else if results.is_empty() {
+                return Err(format!("Action refused: {}", res));
+            }
+            else {
+                results.push(res);
+                break;
+            },
+            Err(e) => if results.is_empty() {
+                return Err(e);
+            }
+            else {
+                //reported like a refused call
+                let mut failure = serde_json::Map::new();
+                failure.insert(String::from("ok"), JsonValue::Bool(false));
+                failure.insert(String::from("description"), JsonValue::String(e));
+                results.push(JsonValue::Object(failure));
+                break;
+            },
+        }
+    }
+    Ok(JsonValue::Array(results))
+}
+
+/// performs a single action
+fn perform(api: &Telegram, chat_id: i64, files_dir: Option<&Path>, action: Action) -> Result<JsonValue, String> {
+    match action {
+        Action::SendMessage { chat_id: target, text, parse_mode, reply_to, reply_markup } => {
+            let reply_to = reply_to.map(|id| id.to_string());
+            api.send_message(&target.unwrap_or(chat_id).to_string(), &text, reply_to.as_ref().map(String::as_str), None, to_parse_mode(parse_mode)?, reply_markup)
+        },
+        Action::SendPhoto { chat_id: target, photo, caption, reply_to, reply_markup } => {
+            let reply_to = reply_to.map(|id| id.to_string());
+            api.send_photo(&target.unwrap_or(chat_id).to_string(), input_file(photo, files_dir)?, caption.as_ref().map(String::as_str), reply_to.as_ref().map(String::as_str), None, reply_markup)
+        },
+        Action::SendLocation { chat_id: target, latitude, longitude, live_period, reply_to, reply_markup } => {
+            let reply_to = reply_to.map(|id| id.to_string());
+            api.send_location(&target.unwrap_or(chat_id).to_string(), latitude, longitude, live_period, reply_to.as_ref().map(String::as_str), reply_markup)
+        },
+        Action::EditLiveLocation { chat_id: target, message_id, latitude, longitude } =>
+            api.edit_message_live_location(&target.unwrap_or(chat_id).to_string(), &message_id.to_string(), latitude, longitude, None),
+        Action::StopLiveLocation { chat_id: target, message_id } =>
+            api.stop_message_live_location(&target.unwrap_or(chat_id).to_string(), &message_id.to_string(), None),
+        Action::Edit { chat_id: target, message_id, text, parse_mode, reply_markup } =>
+            api.edit_message_text(&target.unwrap_or(chat_id).to_string(), &message_id.to_string(), &text, to_parse_mode(parse_mode)?, reply_markup),
+        Action::Delete { chat_id: target, message_id } =>
+            api.delete_message(&target.unwrap_or(chat_id).to_string(), &message_id.to_string()),
+    }
+}
+
+fn to_parse_mode(parse_mode: Option<String>) -> Result<Option<ParseMode>, String> {
+    match parse_mode {
+        Some(mode) => match mode.as_str() {
+            "Markdown" => Ok(Some(ParseMode::Markdown)),
+            "HTML" => Ok(Some(ParseMode::HTML)),
+            _ => Err(format!("Unknown parse_mode {}", mode)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// file_ids never start with a slash nor are URLs
+/// local files are uploaded only from inside files_dir, or any output echoing user input could leak server files
+fn input_file(photo: String, files_dir: Option<&Path>) -> Result<InputFile, String> {
+    if photo.starts_with("http://") || photo.starts_with("https://") {
+        Ok(InputFile::Url(photo))
+    }
+    else if photo.starts_with('/') {
+        let dir = files_dir.ok_or(format!("Local file {} refused, files_dir not configured", photo))?;
+        //symlinks and .. are resolved before checking
+        let dir = fs::canonicalize(dir).map_err(|e| format!("Unable to resolve files_dir {:?}: {:?}", dir, e))?;
+        let path = fs::canonicalize(&photo).map_err(|e| format!("Unable to resolve file {}: {:?}", photo, e))?;
+        if path.starts_with(&dir) {
+            Ok(InputFile::File(path.to_string_lossy().into_owned()))
+        }
+        else {
+            Err(format!("Local file {} refused, outside files_dir", photo))
+        }
+    }
+    else {
+        Ok(InputFile::FileId(photo))
+    }
+}
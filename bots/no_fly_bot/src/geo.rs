@@ -0,0 +1,238 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use serde_json;
+use serde_json::value::Value as JsonValue;
+
+/// mean Earth radius, in meters
+pub const EARTH_RADIUS: f64 = 6371008.8;
+
+/// a point, in degrees
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Point {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Point {
+    pub fn new(latitude: f64, longitude: f64) -> Point {
+        Point {
+            latitude: latitude,
+            longitude: longitude,
+        }
+    }
+
+    /// great-circle distance, in meters, using the haversine formula
+    pub fn distance(&self, other: &Point) -> f64 {
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS * a.sqrt().min(1.0).asin()
+    }
+}
+
+/// a polygon: the outer ring and its holes, as closed lists of points
+#[derive(Debug)]
+struct Polygon {
+    rings: Vec<Vec<Point>>,
+}
+
+impl Polygon {
+    fn contains(&self, point: &Point) -> bool {
+        match self.rings.split_first() {
+            Some((outer, holes)) => ring_contains(outer, point) && !holes.iter().any(|hole| ring_contains(hole, point)),
+            None => false,
+        }
+    }
+}
+
+/// ray casting, good enough for zones not crossing the antimeridian
+fn ring_contains(ring: &[Point], point: &Point) -> bool {
+    let mut inside = false;
+    let mut j = ring.len() - 1;
+    for i in 0..ring.len() {
+        let (a, b) = (&ring[i], &ring[j]);
+        if (a.latitude > point.latitude) != (b.latitude > point.latitude)
+            && point.longitude < (b.longitude - a.longitude) * (point.latitude - a.latitude) / (b.latitude - a.latitude) + a.longitude {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+/// distance, in meters, from a point to a segment
+/// the nearest point is found on a local equirectangular projection, then measured with haversine
+fn segment_distance(point: &Point, a: &Point, b: &Point) -> f64 {
+    let scale = point.latitude.to_radians().cos();
+    let (ax, ay) = ((a.longitude - point.longitude) * scale, a.latitude - point.latitude);
+    let (bx, by) = ((b.longitude - point.longitude) * scale, b.latitude - point.latitude);
+    let (dx, dy) = (bx - ax, by - ay);
+    let length = dx * dx + dy * dy;
+    let t = if length == 0.0 { 0.0 } else { (-(ax * dx + ay * dy) / length).max(0.0).min(1.0) };
+    let nearest = Point::new(a.latitude + t * (b.latitude - a.latitude), a.longitude + t * (b.longitude - a.longitude));
+    point.distance(&nearest)
+}
+
+/// #Zone
+/// A named no-fly zone, made of one or more polygons
+#[derive(Debug)]
+pub struct Zone {
+    name: String,
+    polygons: Vec<Polygon>,
+}
+
+impl Zone {
+    /// returns name
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// checks if a point lies inside the zone
+    pub fn contains(&self, point: &Point) -> bool {
+        self.polygons.iter().any(|polygon| polygon.contains(point))
+    }
+
+    /// distance, in meters, from a point to the zone border, 0 if the point is inside
+    pub fn distance(&self, point: &Point) -> f64 {
+        if self.contains(point) {
+            return 0.0;
+        }
+        let mut min = ::std::f64::INFINITY;
+        for ring in self.polygons.iter().flat_map(|polygon| polygon.rings.iter()) {
+            for pair in ring.windows(2) {
+                min = min.min(segment_distance(point, &pair[0], &pair[1]));
+            }
+        }
+        min
+    }
+}
+
+/// zones already parsed, by file path and modification time
+static ZONES_CACHE: Mutex<Vec<(PathBuf, SystemTime, Arc<Vec<Zone>>)>> = Mutex::new(Vec::new());
+
+/// reads zones from a GeoJSON file like load_zones, parsing it again only when the file is modified
+pub fn cached_zones(path: &Path) -> Result<Arc<Vec<Zone>>, String> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).map_err(|e| format!("Unable to read zones file {:?}: {:?}", path, e))?;
+    let mut cache = ZONES_CACHE.lock().map_err(|e| format!("Zones cache poisoned: {}", e))?;
+    match cache.iter().find(|&&(ref p, m, _)| p == path && m == modified) {
+        Some(&(_, _, ref zones)) => { return Ok(zones.clone()); },
+        None => {},
+    }
+    let zones = Arc::new(load_zones(path)?);
+    cache.retain(|&(ref p, _, _)| p != path);
+    cache.push((path.to_path_buf(), modified, zones.clone()));
+    Ok(zones)
+}
+
+/// reads zones from a GeoJSON file, see parse_zones
+pub fn load_zones(path: &Path) -> Result<Vec<Zone>, String> {
+    let mut s = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut s)).map_err(|e| format!("Unable to read zones file {:?}: {:?}", path, e))?;
+    let json = serde_json::from_str(&s).map_err(|e| format!("Syntax error on zones file {:?}: {}", path, e))?;
+    parse_zones(&json)
+}
+
+/// reads zones from a GeoJSON FeatureCollection of Polygon and MultiPolygon features,
+/// named by their "name" property, other geometries are ignored
+pub fn parse_zones(json: &JsonValue) -> Result<Vec<Zone>, String> {
+    let features = json["features"].as_array().ok_or(String::from("GeoJSON FeatureCollection expected"))?;
+    let mut zones = Vec::new();
+    for (i, feature) in features.iter().enumerate() {
+        let name = feature["properties"]["name"].as_str().map(String::from).unwrap_or(format!("Zone {}", i + 1));
+        let coordinates = &feature["geometry"]["coordinates"];
+        let polygons = match feature["geometry"]["type"].as_str() {
+            Some("Polygon") => vec![parse_polygon(coordinates).map_err(|e| format!("{}: {}", name, e))?],
+            Some("MultiPolygon") => coordinates.as_array().ok_or(format!("{}: invalid MultiPolygon", name))?
+                .iter()
+                .map(|polygon| parse_polygon(polygon).map_err(|e| format!("{}: {}", name, e)))
+                .collect::<Result<Vec<Polygon>, String>>()?,
+            _ => { continue; },
+        };
+        zones.push(Zone {
+            name: name,
+            polygons: polygons,
+        });
+    }
+    Ok(zones)
+}
+
+fn parse_polygon(json: &JsonValue) -> Result<Polygon, String> {
+    let rings = json.as_array().ok_or(String::from("invalid Polygon"))?
+        .iter()
+        .map(|ring| {
+            let mut points = ring.as_array().ok_or(String::from("invalid ring"))?
+                .iter()
+                //GeoJSON positions are longitude first
+                .map(|position| match (position[0].as_f64(), position[1].as_f64()) {
+                    (Some(longitude), Some(latitude)) => Ok(Point::new(latitude, longitude)),
+                    _ => Err(format!("invalid position {}", position)),
+                })
+                .collect::<Result<Vec<Point>, String>>()?;
+            if points.len() < 3 {
+                return Err(String::from("rings need at least 3 positions"));
+            }
+            //rings should be closed already, but some editors don't
+            if points.first() != points.last() {
+                let first = points[0];
+                points.push(first);
+            }
+            Ok(points)
+        })
+        .collect::<Result<Vec<Vec<Point>>, String>>()?;
+    if rings.is_empty() {
+        return Err(String::from("Polygon without rings"));
+    }
+    Ok(Polygon {
+        rings: rings,
+    })
+}
+
+/// converts meters to a short human readable distance
+pub fn format_distance(meters: f64) -> String {
+    if meters < 1000.0 {
+        format!("{:.0} m", meters)
+    }
+    else {
+        format!("{:.1} km", meters / 1000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Point, parse_zones};
+    use serde_json;
+
+    #[test]
+    fn it_works() {
+        //Rome to Milan is about 477 km
+        let rome = Point::new(41.9028, 12.4964);
+        let milan = Point::new(45.4642, 9.1900);
+        assert!((rome.distance(&milan) / 1000.0 - 477.0).abs() < 2.0);
+
+        let zones = parse_zones(&serde_json::from_str(r#"{"type":"FeatureCollection","features":[
+            {"type":"Feature","properties":{"name":"Airport"},"geometry":{"type":"Polygon","coordinates":[
+                [[12.0,45.0],[12.5,45.0],[12.5,45.5],[12.0,45.5],[12.0,45.0]],
+                [[12.2,45.2],[12.3,45.2],[12.3,45.3],[12.2,45.3]]
+            ]}},
+            {"type":"Feature","properties":{},"geometry":{"type":"Point","coordinates":[12.0,45.0]}}
+        ]}"#).unwrap()).unwrap();
+        assert_eq!(zones.len(), 1);
+        let airport = &zones[0];
+        assert_eq!(airport.get_name(), "Airport");
+        assert!(airport.contains(&Point::new(45.1, 12.1)));
+        //holes are outside
+        assert!(!airport.contains(&Point::new(45.25, 12.25)));
+        assert!(!airport.contains(&Point::new(45.1, 12.6)));
+
+        assert_eq!(airport.distance(&Point::new(45.1, 12.1)), 0.0);
+        //0.1 degrees of longitude east of the border, at 45° latitude, is about 7.9 km
+        assert!((airport.distance(&Point::new(45.25, 12.6)) - 7862.0).abs() < 50.0);
+
+        assert!(parse_zones(&serde_json::from_str(r#"{"features":[{"geometry":{"type":"Polygon","coordinates":[[[12.0,45.0]]]}}]}"#).unwrap()).is_err());
+    }
+}
@@ -9,23 +9,54 @@
 
 extern crate client_lib;
 extern crate toml;
+#[macro_use]
+extern crate serde_derive;
 extern crate serde_json;
+#[cfg(unix)]
+extern crate libc;
+
+mod actions;
+mod geo;
+mod sandbox;
 
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::{Arc, RwLock};
-use std::process::Command;
 
 use client_lib::{Bot, Telegram};
 use client_lib::entities::{Request, Message, ParseMode};
+use client_lib::router::{Args, Router};
+use client_lib::session::Session;
+
+use geo::Point;
+use sandbox::{CommandConfig, Protocol};
 
 use serde_json::value::Value as JsonValue;
 
 use toml::Value as TomlValue;
 
+/// user session key holding the last known locations
+const HISTORY_KEY: &'static str = "locations";
+/// user session key holding the zones the user is in
+const ZONES_KEY: &'static str = "zones";
+/// default number of locations remembered per user
+const DEFAULT_HISTORY_SIZE: usize = 20;
+/// default /find radius, in km
+const DEFAULT_FIND_RADIUS: f64 = 10.0;
+
+/// a location received from a user
+#[derive(Debug, Serialize, Deserialize)]
+struct TrackedLocation {
+    point: Point,
+    date: u64,
+}
+
 struct NoFlyBot {
     api: Telegram,
     config: Arc<RwLock<TomlValue>>,
-    _session: Arc<RwLock<HashMap<String, JsonValue>>>,
+    session: Session,
+    //commands are configured executables, the router only parses them
+    router: Router<NoFlyBot>,
 }
 
 impl Bot for NoFlyBot {
@@ -33,10 +64,15 @@ impl Bot for NoFlyBot {
         NoFlyBot {
             api: api,
             config: config.clone(),
-            _session: session.clone(),
+            session: Session::new(session),
+            router: Router::from_config(config).prefixes(&['/', '#']),
         }
     }
 
+    fn get_api(&self) -> Option<&Telegram> {
+        Some(&self.api)
+    }
+
     fn parse_message(&self, request: &Request) -> Result<(String, Vec<String>), String> {
         self.get_command_and_arguments(request.get_message())
     }
@@ -46,66 +82,157 @@ impl Bot for NoFlyBot {
     }
 
     fn dispatch(&self, method: &str, args: Vec<String>, request: &Request) -> Result<JsonValue, String> {
-        match self.config.read() {
-            Ok(config) => match config["commands"].get(method) {
-                Some(exe) => match exe.as_str() {
-                    Some(path) => {
-                        let mut new_args: Vec<String> = Vec::new();
-
-                        let (chat_id, user_id) = match request.get_message() {
-                            &Some(ref msg) => (msg.get_chat().get_id().to_string(), msg.get_from().get_id().to_string()),
-                            &None => match request.get_edited_message() {
-                                &Some(ref msg) => (msg.get_chat().get_id().to_string(), msg.get_from().get_id().to_string()),
-                                &None => {
-                                    return Err(String::from("Unsupported message type"));
-                                },
-                            },
-                        };
-
-                        new_args.push(chat_id.clone());
-                        new_args.push(user_id);
-                        for s in args {
-                            new_args.push(s);
-                        }
-
-                        match Command::new(path).args(&new_args).output() {
-                            Ok(out) => self.api.send_message(&chat_id, &String::from_utf8_lossy(&out.stdout), None, None, Some(ParseMode::Markdown), None),
-                            Err(e) => Err(format!("Error executing {}: {:?}", method, e)),
-                        }
-                    },
-                    None => Err(format!("Command {} incorrectly configured", method)),
-                },
-                None => Err(format!("Command {} not configured", method)),
+        let message = match (request.get_message(), request.get_edited_message()) {
+            (&Some(ref msg), _) | (_, &Some(ref msg)) => msg,
+            _ => { return Err(String::from("Unsupported message type")); },
+        };
+
+        //locations are tracked even if a set_location command is configured too
+        let tracked = if method == "set_location" { Some(self.track_location(message, &args)?) } else { None };
+
+        //settings are copied, so that config isn't locked while the command runs
+        let command = match self.config.read() {
+            Ok(config) => match config.get("commands").and_then(|commands| commands.get(method)) {
+                Some(value) => Some(CommandConfig::from_config(method, value)?),
+                None => None,
             },
-            Err(e) => Err(format!("Error read locking config: {:?}", e)),
+            Err(e) => { return Err(format!("Error read locking config: {:?}", e)); },
+        };
+
+        match (command, tracked) {
+            (Some(c), _) => self.run_command(c, message, args, request),
+            (None, Some(res)) => Ok(res),
+            (None, None) if method == "find" => self.find(message, &Args::new(args)),
+            (None, None) => Err(format!("Command {} not configured", method)),
         }
     }
 }
 
 impl NoFlyBot {
+    /// runs a configured command, sending back its output
+    fn run_command(&self, command: CommandConfig, message: &Message, args: Vec<String>, request: &Request) -> Result<JsonValue, String> {
+        let chat_id = message.get_chat().get_id();
+        let mut new_args: Vec<String> = Vec::new();
+        new_args.push(chat_id.to_string());
+        new_args.push(message.get_from().get_id().to_string());
+        for s in args {
+            new_args.push(s);
+        }
+
+        //json protocol commands receive the whole Request
+        let input = match command.get_protocol() {
+            Protocol::Json => Some(serde_json::to_vec(request).map_err(|e| format!("Unable to serialize request: {}", e))?),
+            Protocol::Args => None,
+        };
+
+        //failures are replied to the user, an error would make Telegram deliver the update again
+        let output = command.run(&new_args, input)?;
+        match command.get_protocol() {
+            Protocol::Json if output.is_success() => {
+                command.log_stderr(&output);
+                actions::execute(&self.api, chat_id, command.get_files_dir(), actions::parse(output.get_stdout())?)
+            },
+            _ => {
+                let (text, markdown) = command.reply(&output);
+                self.api.send_message(&chat_id.to_string(), &text, None, None, if markdown { Some(ParseMode::Markdown) } else { None }, None)
+            },
+        }
+    }
+
+    /// stores a location in user's history, then alerts about entered and left zones
+    fn track_location(&self, message: &Message, args: &[String]) -> Result<JsonValue, String> {
+        let args = Args::new(args.to_vec());
+        let point = Point::new(args.get(1, "latitude")?, args.get(0, "longitude")?);
+        let user = self.session.user(message.get_from().get_id());
+
+        let mut history = user.get::<Vec<TrackedLocation>>(HISTORY_KEY)?.unwrap_or_default();
+        history.push(TrackedLocation {
+            point: point,
+            //live locations are updated by editing the message
+            date: message.get_edit_date().unwrap_or(message.get_date()),
+        });
+        let size = self.get_config_integer("HISTORY_SIZE")?.map(|s| s as usize).unwrap_or(DEFAULT_HISTORY_SIZE);
+        if history.len() > size {
+            let excess = history.len() - size;
+            history.drain(..excess);
+        }
+        user.set(HISTORY_KEY, &history)?;
+
+        let zones = match self.load_zones()? {
+            Some(z) => z,
+            None => { return Ok(JsonValue::Null); },
+        };
+        let current: Vec<String> = zones.iter().filter(|zone| zone.contains(&point)).map(|zone| zone.get_name().to_owned()).collect();
+        let previous = user.get::<Vec<String>>(ZONES_KEY)?.unwrap_or_default();
+        user.set(ZONES_KEY, &current)?;
+
+        let enter_alert = self.get_config_string("ENTER_ALERT")?.unwrap_or(String::from("Entered no-fly zone {zone}"));
+        let exit_alert = self.get_config_string("EXIT_ALERT")?.unwrap_or(String::from("Left no-fly zone {zone}"));
+        let alerts: Vec<String> = previous.iter().filter(|zone| !current.contains(zone)).map(|zone| exit_alert.replace("{zone}", zone))
+            .chain(current.iter().filter(|zone| !previous.contains(zone)).map(|zone| enter_alert.replace("{zone}", zone)))
+            .collect();
+        if alerts.is_empty() {
+            return Ok(JsonValue::Null);
+        }
+        self.api.send_message(&message.get_chat().get_id().to_string(), &alerts.join("\n"), None, None, None, None)
+    }
+
+    /// lists zones within a radius, in km, from user's last known location
+    fn find(&self, message: &Message, args: &Args) -> Result<JsonValue, String> {
+        let chat_id = message.get_chat().get_id().to_string();
+        let zones = self.load_zones()?.ok_or(String::from("ZONES config value not found"))?;
+        let radius = args.optional::<f64>(0, "radius")?.unwrap_or(DEFAULT_FIND_RADIUS);
+        let last = match self.session.user(message.get_from().get_id()).get::<Vec<TrackedLocation>>(HISTORY_KEY)?.and_then(|mut h| h.pop()) {
+            Some(l) => l,
+            None => { return self.api.send_message(&chat_id, "Send your location first", None, None, None, None); },
+        };
+
+        let mut nearby: Vec<(f64, &str)> = zones.iter()
+            .map(|zone| (zone.distance(&last.point), zone.get_name()))
+            .filter(|&(distance, _)| distance <= radius * 1000.0)
+            .collect();
+        nearby.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(::std::cmp::Ordering::Equal));
+        let text = if nearby.is_empty() {
+            format!("No zones within {} km", radius)
+        }
+        else {
+            nearby.iter()
+                .map(|&(distance, name)| if distance == 0.0 { format!("{}: inside", name) } else { format!("{}: {}", name, geo::format_distance(distance)) })
+                .collect::<Vec<String>>()
+                .join("\n")
+        };
+        self.api.send_message(&chat_id, &text, None, None, None, None)
+    }
+
+    /// loads zones from the GeoJSON file set in ZONES config value, if any, the file is parsed again only when modified
+    fn load_zones(&self) -> Result<Option<Arc<Vec<geo::Zone>>>, String> {
+        match self.get_config_string("ZONES")? {
+            Some(path) => geo::cached_zones(Path::new(&path)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn get_config_string(&self, key: &str) -> Result<Option<String>, String> {
+        let config = self.config.read().map_err(|e| format!("Error read locking config: {:?}", e))?;
+        match config.get(key) {
+            Some(value) => value.as_str().map(|v| Some(v.to_owned())).ok_or(format!("Error interpreting {} config value", key)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_config_integer(&self, key: &str) -> Result<Option<i64>, String> {
+        let config = self.config.read().map_err(|e| format!("Error read locking config: {:?}", e))?;
+        match config.get(key) {
+            Some(value) => value.as_integer().map(Some).ok_or(format!("Error interpreting {} config value", key)),
+            None => Ok(None),
+        }
+    }
+
     fn get_command_and_arguments(&self, message: &Option<Box<Message>>) ->Result<(String, Vec<String>), String> {
         match message {
             &Some(ref msg) => {
                 match msg.get_text() {
-                    &Some(ref txt) => {
-                        let text = txt.trim();
-                        if text.starts_with('/') || text.starts_with('#') {
-                            let mut words: Vec<String> = Vec::new();
-                            for s in text.split(' ') {
-                                words.push(String::from(s));
-                            }
-
-                            //remove first char
-                            let method = words.swap_remove(0);
-                            let mut chars = method.chars();
-                            chars.next();
-
-                            Ok((String::from(chars.as_str()), words))
-                        }
-                        else {
-                            Err(format!("String \"{}\" doesn't contains a command", text))
-                        }
-                    },
+                    &Some(ref txt) => self.router.parse_message(msg).ok_or(format!("String \"{}\" doesn't contains a command", txt.trim())),
                     &None => match msg.get_location() {
                         &Some(ref loc) => Ok((String::from("set_location"), vec![loc.get_longitude().to_string(), loc.get_latitude().to_string()])),
                         &None => Err(String::from("Unsupported message type")),
@@ -141,20 +268,29 @@ pub extern fn init_bot(ptr_config: *const Arc<RwLock<TomlValue>>, ptr_session: *
 
 #[cfg(test)]
 mod tests {
-    use super::{toml, serde_json, init_bot};
+    use super::{toml, serde_json, init_bot, TrackedLocation, HISTORY_KEY};
     use super::client_lib::entities::Request;
+    use super::client_lib::session::Session;
+    use super::client_lib::mock::{MockServer, json_ok};
+    use super::serde_json::value::Value as JsonValue;
     use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::process;
     use std::sync::{Arc, RwLock};
 
     #[test]
     fn it_works() {
-        let config: toml::Value = toml::from_str(r#"SECRET = "prova"
+        let server = MockServer::start().unwrap();
+        let config: toml::Value = toml::from_str(&format!(r#"SECRET = "prova"
 HTTP_TOKEN = "test"
+API_URL = "{}"
 
-[command]
-set_position = "echo"
+[commands]
+set_location = "echo"
 find = "echo"
-"#).unwrap();
+"#, server.get_url())).unwrap();
+        let config = Arc::new(RwLock::new(config));
 
         let messages = [r#"{
 "update_id":10000,
@@ -175,7 +311,8 @@ find = "echo"
      "first_name":"Test Firstname",
      "username":"Testusername"
   },
-  "text":"/find pippo"
+  "text":"/find pippo",
+  "entities":[{"type":"bot_command","offset":0,"length":5}]
 }
 }"#, r#"{
   "update_id":241066349,
@@ -204,10 +341,103 @@ find = "echo"
     }
   }
 }"#];
-        for s in messages.iter() {
+        let expected = [
+            "1111111 1111111 pippo\n",
+            "1111111 1111111 12.233439 45.5589\n",
+        ];
+        for (s, text) in messages.iter().zip(expected.iter()) {
             let request: Request = serde_json::from_str(s).unwrap();
+            let res = unsafe { &*init_bot(Box::into_raw(Box::new(config.clone())), Box::into_raw(Box::new(Arc::new(RwLock::new(HashMap::new())))), "prova", Box::into_raw(Box::new(&request))) };
 
-            assert_eq!(init_bot(Box::into_raw(Box::new(Arc::new(RwLock::new(config)))), Box::into_raw(Box::new(Arc::new(RwLock::new(HashMap::new())))), "prova", Box::into_raw(Box::new(request))), Err(String::from("TODO")));
+            assert_eq!(res, &Ok(json_ok()));
+            assert!(server.was_called("sendMessage", &[("chat_id", "1111111"), ("text", text), ("parse_mode", "Markdown")]));
         }
+        assert_eq!(server.get_calls().len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn json_protocol() {
+        use std::os::unix::fs::PermissionsExt;
+
+        //replies to the message, quoting its update_id, then sends a location
+        let mut script = env::temp_dir();
+        script.push(format!("no_fly_bot_json_protocol_{}.sh", process::id()));
+        fs::write(&script, r#"#!/bin/sh
+id=$(cat | sed 's/.*"update_id":\([0-9]*\).*/\1/')
+echo '[{"action":"send_message","text":"update '$id'","reply_to":1365},{"action":"send_location","latitude":45.5589,"longitude":12.233439},{"action":"send_photo","photo":"/etc/passwd"},{"action":"delete","message_id":1}]'
+"#).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let server = MockServer::start().unwrap();
+        server.respond("sendMessage", json_ok());
+        server.respond("sendLocation", json_ok());
+        let config: toml::Value = toml::from_str(&format!(r#"SECRET = "prova"
+HTTP_TOKEN = "test"
+API_URL = "{}"
+
+[commands.zones]
+path = "{}"
+protocol = "json"
+"#, server.get_url(), script.display())).unwrap();
+        let request: Request = serde_json::from_str(r#"{"update_id":10001,"message":{"message_id":1365,"date":0,"chat":{"id":1111111,"type":"private"},"from":{"is_bot":false,"id":1111111,"first_name":"Test"},"text":"/zones","entities":[{"type":"bot_command","offset":0,"length":6}]}}"#).unwrap();
+        let res = unsafe { &*init_bot(Box::into_raw(Box::new(Arc::new(RwLock::new(config)))), Box::into_raw(Box::new(Arc::new(RwLock::new(HashMap::new())))), "prova", Box::into_raw(Box::new(&request))) };
+
+        //local files outside files_dir are refused, stopping the actions,
+        //yet the update succeeds since previous actions already took effect
+        let results = res.clone().unwrap();
+        assert_eq!(results.as_array().map(|r| r.len()), Some(3));
+        assert_eq!(results[2]["ok"], JsonValue::Bool(false));
+        assert!(server.was_called("sendMessage", &[("chat_id", "1111111"), ("text", "update 10001"), ("reply_to_message_id", "1365")]));
+        assert!(server.was_called("sendLocation", &[("chat_id", "1111111"), ("latitude", "45.5589"), ("longitude", "12.233439")]));
+        assert!(server.get_calls_to("sendPhoto").is_empty());
+        assert!(server.get_calls_to("deleteMessage").is_empty());
+    }
+
+    #[test]
+    fn geofencing() {
+        let mut zones = env::temp_dir();
+        zones.push(format!("no_fly_bot_geofencing_{}.geojson", process::id()));
+        fs::write(&zones, r#"{"type":"FeatureCollection","features":[
+            {"type":"Feature","properties":{"name":"Airport"},"geometry":{"type":"Polygon","coordinates":[[[12.0,45.0],[12.5,45.0],[12.5,45.5],[12.0,45.5],[12.0,45.0]]]}},
+            {"type":"Feature","properties":{"name":"Base"},"geometry":{"type":"Polygon","coordinates":[[[13.0,45.0],[13.1,45.0],[13.1,45.1],[13.0,45.1],[13.0,45.0]]]}}
+        ]}"#).unwrap();
+
+        let server = MockServer::start().unwrap();
+        server.respond("sendMessage", json_ok());
+        let config = Arc::new(RwLock::new(toml::from_str::<toml::Value>(&format!(r#"SECRET = "prova"
+HTTP_TOKEN = "test"
+API_URL = "{}"
+ZONES = "{}"
+HISTORY_SIZE = 2
+"#, server.get_url(), zones.display())).unwrap()));
+        let session = Arc::new(RwLock::new(HashMap::new()));
+        let run = |json: String| {
+            let request: Request = serde_json::from_str(&json).unwrap();
+            unsafe { &*init_bot(Box::into_raw(Box::new(config.clone())), Box::into_raw(Box::new(session.clone())), "prova", Box::into_raw(Box::new(&request))) }.clone()
+        };
+        let live = |latitude: f64, longitude: f64| format!(r#"{{"update_id":1,"edited_message":{{"message_id":4,"date":1520764899,"edit_date":1520764972,"chat":{{"id":1111111,"type":"private"}},"from":{{"is_bot":false,"id":1111111,"first_name":"Test"}},"location":{{"latitude":{},"longitude":{}}}}}}}"#, latitude, longitude);
+
+        //outside every zone
+        assert_eq!(run(live(44.9, 12.2)), Ok(JsonValue::Null));
+        assert!(server.get_calls().is_empty());
+
+        run(live(45.2, 12.2)).unwrap();
+        assert!(server.was_called("sendMessage", &[("chat_id", "1111111"), ("text", "Entered no-fly zone Airport")]));
+        server.clear();
+        assert_eq!(run(live(45.3, 12.3)), Ok(JsonValue::Null));
+        assert!(server.get_calls().is_empty());
+        run(live(45.05, 12.9)).unwrap();
+        assert!(server.was_called("sendMessage", &[("text", "Left no-fly zone Airport")]));
+
+        //history is capped
+        let history = Session::new(&session).user(1111111).get::<Vec<TrackedLocation>>(HISTORY_KEY).unwrap().map(|h| h.len());
+        assert_eq!(history, Some(2));
+
+        let find = |args: &str| format!(r#"{{"update_id":2,"message":{{"message_id":5,"date":1520764999,"chat":{{"id":1111111,"type":"private"}},"from":{{"is_bot":false,"id":1111111,"first_name":"Test"}},"text":"/find{}","entities":[{{"type":"bot_command","offset":0,"length":5}}]}}}}"#, args);
+        run(find("")).unwrap();
+        assert!(server.was_called("sendMessage", &[("text", "Base: 7.9 km")]));
+        run(find(" 40")).unwrap();
+        assert!(server.was_called("sendMessage", &[("text", "Base: 7.9 km\nAirport: 31.4 km")]));
     }
 }
@@ -0,0 +1,450 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use toml::Value as TomlValue;
+
+/// default seconds a command can run
+pub const DEFAULT_TIMEOUT: u64 = 30;
+/// default bytes kept from stdout and stderr
+pub const DEFAULT_MAX_OUTPUT: usize = 4096;
+/// Telegram messages are 4096 chars at most, longer replies are cut
+pub const MAX_MESSAGE_LENGTH: usize = 4096;
+/// appended to cut output
+const ELLIPSIS: char = '…';
+/// how often a running command is checked
+const POLL_INTERVAL: u64 = 50;
+
+/// what to do with a command's stderr
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StderrMode {
+    /// print it in server log
+    Log,
+    /// append it to the reply
+    Reply,
+    /// discard it
+    Ignore,
+}
+
+/// how a command talks with NoFlyBot
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    /// arguments are passed as argv, stdout is sent back as a message
+    Args,
+    /// Request json is written to stdin too, stdout is a json list of actions, see actions::Action
+    Json,
+}
+
+/// how a command runs, read from its NoFlyBot.toml entry
+/// a plain string is just the executable path, with default settings
+#[derive(Debug)]
+pub struct CommandConfig {
+    name: String,
+    path: String,
+    timeout: Duration,
+    cwd: Option<PathBuf>,
+    files_dir: Option<PathBuf>,
+    env: Vec<String>,
+    max_output: usize,
+    stderr: StderrMode,
+    protocol: Protocol,
+    exit_codes: HashMap<i32, String>,
+    error_reply: String,
+    timeout_reply: String,
+    cpu_limit: Option<u64>,
+    memory_limit: Option<u64>,
+}
+
+impl CommandConfig {
+    pub fn from_config(name: &str, value: &TomlValue) -> Result<CommandConfig, String> {
+        let mut config = CommandConfig {
+            name: name.to_owned(),
+            path: String::new(),
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT),
+            cwd: None,
+            files_dir: None,
+            //commands are looked up in PATH
+            env: vec![String::from("PATH")],
+            max_output: DEFAULT_MAX_OUTPUT,
+            stderr: StderrMode::Log,
+            protocol: Protocol::Args,
+            exit_codes: HashMap::new(),
+            error_reply: String::from("Command failed"),
+            timeout_reply: String::from("Command timed out"),
+            cpu_limit: None,
+            memory_limit: None,
+        };
+
+        let table = match value {
+            &TomlValue::String(ref path) => {
+                config.path = path.clone();
+                return Ok(config);
+            },
+            &TomlValue::Table(ref table) => table,
+            _ => { return Err(format!("Command {} incorrectly configured", name)); },
+        };
+
+        config.path = CommandConfig::read_str(name, table.get("path"), "path")?.ok_or(format!("Command {} has no path", name))?;
+        match CommandConfig::read_u64(name, table.get("timeout"), "timeout")? {
+            Some(secs) => { config.timeout = Duration::from_secs(secs); },
+            None => {},
+        }
+        config.cwd = CommandConfig::read_str(name, table.get("cwd"), "cwd")?.map(PathBuf::from);
+        config.files_dir = CommandConfig::read_str(name, table.get("files_dir"), "files_dir")?.map(PathBuf::from);
+        match table.get("env") {
+            Some(value) => {
+                config.env = value.as_array().ok_or(format!("Error interpreting {}.env value", name))?
+                    .iter()
+                    .map(|var| var.as_str().map(String::from).ok_or(format!("Invalid environment variable {} for {}", var, name)))
+                    .collect::<Result<Vec<String>, String>>()?;
+            },
+            None => {},
+        }
+        match CommandConfig::read_u64(name, table.get("max_output"), "max_output")? {
+            Some(bytes) => { config.max_output = bytes as usize; },
+            None => {},
+        }
+        match CommandConfig::read_str(name, table.get("stderr"), "stderr")? {
+            Some(mode) => {
+                config.stderr = match mode.as_str() {
+                    "log" => StderrMode::Log,
+                    "reply" => StderrMode::Reply,
+                    "ignore" => StderrMode::Ignore,
+                    _ => { return Err(format!("Unknown stderr mode {} for {}", mode, name)); },
+                };
+            },
+            None => {},
+        }
+        match CommandConfig::read_str(name, table.get("protocol"), "protocol")? {
+            Some(protocol) => {
+                config.protocol = match protocol.as_str() {
+                    "args" => Protocol::Args,
+                    "json" => Protocol::Json,
+                    _ => { return Err(format!("Unknown protocol {} for {}", protocol, name)); },
+                };
+            },
+            None => {},
+        }
+        match table.get("exit_codes") {
+            Some(value) => {
+                for (code, reply) in value.as_table().ok_or(format!("Error interpreting {}.exit_codes value", name))? {
+                    let code = code.parse::<i32>().map_err(|_| format!("Invalid exit code {} for {}", code, name))?;
+                    let reply = reply.as_str().ok_or(format!("Error interpreting {}.exit_codes.{} value", name, code))?;
+                    config.exit_codes.insert(code, reply.to_owned());
+                }
+            },
+            None => {},
+        }
+        match CommandConfig::read_str(name, table.get("error_reply"), "error_reply")? {
+            Some(reply) => { config.error_reply = reply; },
+            None => {},
+        }
+        match CommandConfig::read_str(name, table.get("timeout_reply"), "timeout_reply")? {
+            Some(reply) => { config.timeout_reply = reply; },
+            None => {},
+        }
+        config.cpu_limit = CommandConfig::read_u64(name, table.get("cpu_limit"), "cpu_limit")?;
+        config.memory_limit = CommandConfig::read_u64(name, table.get("memory_limit"), "memory_limit")?;
+
+        Ok(config)
+    }
+
+    fn read_str(name: &str, value: Option<&TomlValue>, key: &str) -> Result<Option<String>, String> {
+        match value {
+            Some(v) => v.as_str().map(|s| Some(s.to_owned())).ok_or(format!("Error interpreting {}.{} value", name, key)),
+            None => Ok(None),
+        }
+    }
+
+    fn read_u64(name: &str, value: Option<&TomlValue>, key: &str) -> Result<Option<u64>, String> {
+        match value {
+            Some(v) => match v.as_integer() {
+                Some(i) if i >= 0 => Ok(Some(i as u64)),
+                _ => Err(format!("Error interpreting {}.{} value", name, key)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// returns the folder json protocol actions can upload local files from, None if not configured
+    pub fn get_files_dir(&self) -> Option<&Path> {
+        self.files_dir.as_ref().map(PathBuf::as_path)
+    }
+
+    /// returns protocol
+    pub fn get_protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// runs the command, writing input to its stdin, killing it when timeout elapses
+    pub fn run(&self, args: &[String], input: Option<Vec<u8>>) -> Result<Output, String> {
+        let mut command = Command::new(&self.path);
+        command.args(args)
+            .env_clear()
+            .envs(self.env.iter().filter_map(|var| env::var_os(var).map(|value| (var.clone(), value))))
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        match self.cwd {
+            Some(ref cwd) => { command.current_dir(cwd); },
+            None => {},
+        }
+        isolate(&mut command, self.cpu_limit, self.memory_limit);
+
+        let mut child = command.spawn().map_err(|e| format!("Error executing {}: {:?}", self.name, e))?;
+        let stdout = read_limited(child.stdout.take(), self.max_output);
+        let stderr = read_limited(child.stderr.take(), self.max_output);
+        //written on its own thread, commands may not read it before writing their output
+        match (child.stdin.take(), input) {
+            (Some(mut stdin), Some(data)) => {
+                thread::spawn(move || {
+                    let _ = stdin.write_all(&data);
+                });
+            },
+            _ => {},
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        let mut timed_out = false;
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break Some(status),
+                Ok(None) => if Instant::now() >= deadline {
+                    timed_out = true;
+                    break None;
+                }
+                else {
+                    thread::sleep(Duration::from_millis(POLL_INTERVAL));
+                },
+                Err(e) => {
+                    terminate(&mut child);
+                    return Err(format!("Error waiting {}: {:?}", self.name, e));
+                },
+            }
+        };
+        //background processes spawned by the command would keep its output open
+        terminate(&mut child);
+
+        let (stdout, truncated) = stdout.join().map_err(|_| format!("Error reading {} output", self.name))?;
+        let (stderr, _) = stderr.join().map_err(|_| format!("Error reading {} errors", self.name))?;
+        Ok(Output {
+            code: status.and_then(|s| s.code()),
+            timed_out: timed_out,
+            truncated: truncated,
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        })
+    }
+
+    /// builds the text to send back: stdout on success, the configured replies on failure,
+    /// and whether it can be sent as Markdown: stderr and cut output could break its entities
+    pub fn reply(&self, output: &Output) -> (String, bool) {
+        let mut reply = match (output.timed_out, output.code) {
+            (true, _) => { return (self.timeout_reply.clone(), true); },
+            (false, Some(0)) => output.stdout.clone(),
+            (false, Some(code)) => self.exit_codes.get(&code).cloned().unwrap_or(self.error_reply.clone()),
+            //killed by a signal, like an exceeded cpu limit
+            (false, None) => self.error_reply.clone(),
+        };
+        let mut markdown = true;
+        let mut truncated = output.truncated && output.code == Some(0);
+        match self.stderr {
+            StderrMode::Reply if !output.stderr.trim().is_empty() => {
+                reply.push('\n');
+                reply.push_str(&output.stderr);
+                markdown = false;
+            },
+            _ => self.log_stderr(output),
+        }
+        //cut the whole message, leaving room for the ellipsis
+        if reply.chars().count() > MAX_MESSAGE_LENGTH {
+            reply = reply.chars().take(MAX_MESSAGE_LENGTH - 1).collect();
+            truncated = true;
+        }
+        if truncated {
+            reply.push(ELLIPSIS);
+            markdown = false;
+        }
+        (reply, markdown)
+    }
+
+    /// prints stderr in server log, if configured so
+    pub fn log_stderr(&self, output: &Output) {
+        match self.stderr {
+            StderrMode::Log if !output.stderr.trim().is_empty() => println!("Command {} stderr: {}", self.name, output.stderr.trim()),
+            _ => {},
+        }
+    }
+}
+
+/// a completed command
+#[derive(Debug)]
+pub struct Output {
+    code: Option<i32>,
+    timed_out: bool,
+    truncated: bool,
+    stdout: String,
+    stderr: String,
+}
+
+impl Output {
+    /// checks if the command completed with exit code 0
+    pub fn is_success(&self) -> bool {
+        !self.timed_out && self.code == Some(0)
+    }
+
+    /// returns stdout
+    pub fn get_stdout(&self) -> &str {
+        &self.stdout
+    }
+}
+
+/// reads a stream on its own thread, keeping at most limit bytes
+/// the rest is drained anyway, or the command would block on a full pipe
+fn read_limited<R: Read + Send + 'static>(reader: Option<R>, limit: usize) -> thread::JoinHandle<(Vec<u8>, bool)> {
+    thread::spawn(move || {
+        let mut out = Vec::new();
+        let mut truncated = false;
+        let mut reader = match reader {
+            Some(r) => r,
+            None => { return (out, truncated); },
+        };
+        let mut buf = [0u8; 4096];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    let room = limit.saturating_sub(out.len());
+                    if n > room {
+                        truncated = true;
+                    }
+                    out.extend_from_slice(&buf[..cmp::min(n, room)]);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => break,
+            }
+        }
+        (out, truncated)
+    })
+}
+
+/// runs the command in its own process group, with the given resource limits
+#[cfg(unix)]
+fn isolate(command: &mut Command, cpu_limit: Option<u64>, memory_limit: Option<u64>) {
+    use std::os::unix::process::CommandExt;
+    use libc;
+
+    fn rlimit(value: u64) -> libc::rlimit {
+        libc::rlimit {
+            rlim_cur: value as libc::rlim_t,
+            rlim_max: value as libc::rlim_t,
+        }
+    }
+
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setpgid(0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            match cpu_limit {
+                Some(secs) => if libc::setrlimit(libc::RLIMIT_CPU, &rlimit(secs)) != 0 {
+                    return Err(io::Error::last_os_error());
+                },
+                None => {},
+            }
+            match memory_limit {
+                Some(bytes) => if libc::setrlimit(libc::RLIMIT_AS, &rlimit(bytes)) != 0 {
+                    return Err(io::Error::last_os_error());
+                },
+                None => {},
+            }
+            Ok(())
+        });
+    }
+}
+
+/// resource limits are only available on unix
+#[cfg(not(unix))]
+fn isolate(_command: &mut Command, _cpu_limit: Option<u64>, _memory_limit: Option<u64>) {}
+
+/// kills the command and every process it spawned, then reaps it
+#[cfg(unix)]
+fn terminate(child: &mut Child) {
+    use libc;
+
+    //the whole group may be already gone
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL); }
+    let _ = child.wait();
+}
+
+/// kills the command, then reaps it
+#[cfg(not(unix))]
+fn terminate(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommandConfig, Output, MAX_MESSAGE_LENGTH};
+    use std::time::{Duration, Instant};
+    use toml;
+
+    fn run(config: &str, script: &str) -> (String, Output) {
+        let config = CommandConfig::from_config("test", &toml::from_str::<toml::Value>(config).unwrap()).unwrap();
+        let output = config.run(&[String::from("-c"), String::from(script)], None).unwrap();
+        (config.reply(&output).0, output)
+    }
+
+    #[test]
+    fn it_works() {
+        let (reply, output) = run("path = \"sh\"", "echo hello; echo oops >&2");
+        assert_eq!(reply, "hello\n");
+        assert_eq!(output.code, Some(0));
+        assert_eq!(output.stderr, "oops\n");
+
+        //exit codes and stderr
+        let config = "path = \"sh\"\nstderr = \"reply\"\n[exit_codes]\n3 = \"Nothing found\"";
+        assert_eq!(run(config, "exit 3").0, "Nothing found");
+        assert_eq!(run(config, "echo broken >&2; exit 1").0, "Command failed\nbroken\n");
+
+        //stdin
+        let config = CommandConfig::from_config("test", &toml::from_str::<toml::Value>("path = \"sh\"\nprotocol = \"json\"").unwrap()).unwrap();
+        let output = config.run(&[String::from("-c"), String::from("cat")], Some(b"{}".to_vec())).unwrap();
+        assert!(output.is_success());
+        assert_eq!(output.get_stdout(), "{}");
+
+        //environment whitelist
+        assert_eq!(run("path = \"sh\"", "echo \"$HOME\"").0, "\n");
+
+        //output limit
+        let (reply, output) = run("path = \"sh\"\nmax_output = 5", "echo 1234567890");
+        assert_eq!(reply, "12345…");
+        assert!(output.truncated);
+
+        //replies fit a Telegram message, stderr included, and aren't sent as Markdown once cut
+        let config = CommandConfig::from_config("test", &toml::from_str::<toml::Value>("path = \"sh\"\nstderr = \"reply\"").unwrap()).unwrap();
+        let output = config.run(&[String::from("-c"), String::from("head -c 5000 /dev/zero | tr '\\0' a; echo oops >&2")], None).unwrap();
+        let (reply, markdown) = config.reply(&output);
+        assert_eq!(reply.chars().count(), MAX_MESSAGE_LENGTH);
+        assert!(reply.ends_with("a…"));
+        assert!(!markdown);
+        let output = config.run(&[String::from("-c"), String::from("echo '*done*'")], None).unwrap();
+        assert_eq!(config.reply(&output), (String::from("*done*\n"), true));
+
+        //timeout kills background processes too
+        let start = Instant::now();
+        let (reply, output) = run("path = \"sh\"\ntimeout = 1", "sleep 10 & sleep 10");
+        assert!(output.timed_out);
+        assert_eq!(reply, "Command timed out");
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        assert!(CommandConfig::from_config("test", &toml::from_str::<toml::Value>("path = \"sh\"\nstderr = \"mail\"").unwrap()).is_err());
+        assert!(CommandConfig::from_config("test", &toml::Value::Integer(3)).is_err());
+    }
+}
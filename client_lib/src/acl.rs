@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+
+use serde_json::value::Value as JsonValue;
+
+use toml::Value as TomlValue;
+
+use entities::Request;
+use Telegram;
+
+/// bot config section holding the ACL
+pub const ACL_SECTION: &'static str = "ACL";
+
+/// what a user needs to run a command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    /// no requirement
+    Anyone,
+    /// administrator or creator of the group the command is sent in, checked with getChatMember
+    ChatAdmin,
+    /// listed in ADMINS
+    Admin,
+}
+
+impl Role {
+    /// parses a role name, as written in bot config
+    pub fn parse(value: &str) -> Result<Role, String> {
+        match value {
+            "anyone" => Ok(Role::Anyone),
+            "chat_admin" => Ok(Role::ChatAdmin),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("Unknown ACL role {}", value)),
+        }
+    }
+}
+
+/// outcome of an ACL check
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    /// the Request can go on
+    Allowed,
+    /// sender or chat excluded by the allow and block lists, silently ignored
+    Blocked,
+    /// sender lacks the Role required by the command, told with DENIED_REPLY
+    Forbidden,
+}
+
+/// #Acl
+/// Access control list read from the [ACL] section of bot's toml, enforced before Bot::dispatch.
+/// Users and chats can be allowed or blocked by id, an empty allow list allows everyone,
+/// commands can require a Role and requests lacking it get DENIED_REPLY, if configured.
+/// Blocked users and chats are never answered, or a bot replying to every message would spam them.
+#[derive(Debug)]
+pub struct Acl {
+    allowed_users: Vec<i64>,
+    blocked_users: Vec<i64>,
+    allowed_chats: Vec<i64>,
+    blocked_chats: Vec<i64>,
+    admins: Vec<i64>,
+    default_role: Role,
+    commands: HashMap<String, Role>,
+    denied_reply: Option<String>,
+}
+
+impl Acl {
+    /// reads the ACL from bot config, None if there's no ACL section
+    pub fn from_config(config: &TomlValue) -> Result<Option<Acl>, String> {
+        let section = match config.get(ACL_SECTION) {
+            Some(s) => s,
+            None => { return Ok(None); },
+        };
+
+        let mut commands = HashMap::new();
+        match section.get("COMMANDS") {
+            Some(value) => {
+                let table = value.as_table().ok_or(String::from("Error interpreting ACL.COMMANDS config value"))?;
+                for (command, role) in table {
+                    let role = role.as_str().ok_or(format!("Error interpreting ACL.COMMANDS.{} config value", command))?;
+                    commands.insert(command.clone(), Role::parse(role)?);
+                }
+            },
+            None => {},
+        }
+
+        Ok(Some(Acl {
+            allowed_users: Acl::read_ids(section, "ALLOWED_USERS")?,
+            blocked_users: Acl::read_ids(section, "BLOCKED_USERS")?,
+            allowed_chats: Acl::read_ids(section, "ALLOWED_CHATS")?,
+            blocked_chats: Acl::read_ids(section, "BLOCKED_CHATS")?,
+            admins: Acl::read_ids(section, "ADMINS")?,
+            default_role: match section.get("DEFAULT_ROLE") {
+                Some(value) => Role::parse(value.as_str().ok_or(String::from("Error interpreting ACL.DEFAULT_ROLE config value"))?)?,
+                None => Role::Anyone,
+            },
+            commands: commands,
+            denied_reply: match section.get("DENIED_REPLY") {
+                Some(value) => Some(value.as_str().ok_or(String::from("Error interpreting ACL.DENIED_REPLY config value"))?.to_owned()),
+                None => None,
+            },
+        }))
+    }
+
+    fn read_ids(section: &TomlValue, key: &str) -> Result<Vec<i64>, String> {
+        match section.get(key) {
+            Some(value) => value.as_array().ok_or(format!("Error interpreting ACL.{} config value", key))?
+                .iter()
+                .map(|id| id.as_integer().ok_or(format!("Invalid id {} in ACL.{} config value", id, key)))
+                .collect(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// returns the role required by a command
+    pub fn get_role(&self, method: &str) -> Role {
+        self.commands.get(method).cloned().unwrap_or(self.default_role)
+    }
+
+    /// checks if the Request sender and chat are allowed, and can run the given command
+    /// without a command only users and chats are checked
+    pub fn authorize(&self, api: &Telegram, method: Option<&str>, request: &Request) -> Result<Access, String> {
+        let user_id = request.get_from().map(|user| user.get_id());
+        match user_id {
+            Some(id) => if self.blocked_users.contains(&id) || (!self.allowed_users.is_empty() && !self.allowed_users.contains(&id)) {
+                return Ok(Access::Blocked);
+            },
+            None => if !self.allowed_users.is_empty() {
+                return Ok(Access::Blocked);
+            },
+        }
+        //inline queries have no chat, only user lists apply
+        let chat = request.get_chat();
+        match chat {
+            Some(c) => if self.blocked_chats.contains(&c.get_id()) || (!self.allowed_chats.is_empty() && !self.allowed_chats.contains(&c.get_id())) {
+                return Ok(Access::Blocked);
+            },
+            None => {},
+        }
+
+        let role = match method {
+            Some(m) => self.get_role(m),
+            None => Role::Anyone,
+        };
+        let is_admin = user_id.map(|id| self.admins.contains(&id)).unwrap_or(false);
+        match (role, user_id, chat) {
+            (Role::Anyone, _, _) => Ok(Access::Allowed),
+            (_, _, _) if is_admin => Ok(Access::Allowed),
+            //private chats have no admins but bot ones
+            (Role::ChatAdmin, Some(user), Some(c)) if c.get_type() != "private" => {
+                let member = api.get_chat_member(&c.get_id().to_string(), &user.to_string())?;
+                match member["result"]["status"].as_str() {
+                    Some("creator") | Some("administrator") => Ok(Access::Allowed),
+                    Some(_) => Ok(Access::Forbidden),
+                    None => Err(format!("Unable to retrieve chat member status: {}", member)),
+                }
+            },
+            _ => Ok(Access::Forbidden),
+        }
+    }
+
+    /// sends DENIED_REPLY, if configured, where the Request came from
+    pub fn deny(&self, api: &Telegram, request: &Request) -> Result<JsonValue, String> {
+        let text = match self.denied_reply {
+            Some(ref t) => t,
+            None => { return Ok(JsonValue::Null); },
+        };
+        match request.get_callback_query() {
+            &Some(ref query) => { return api.answer_callback_query(query.get_id(), Some(text), Some(true), None, None); },
+            &None => {},
+        }
+        match request.get_chat() {
+            Some(chat) => api.send_message(&chat.get_id().to_string(), text, None, None, None, None),
+            None => Ok(JsonValue::Null),
+        }
+    }
+}
+
+/// enforces the ACL configured in bot's toml, if any
+/// returns the denial result when the Request isn't allowed, None when it can go on
+pub fn enforce(api: &Telegram, method: Option<&str>, request: &Request) -> Result<Option<JsonValue>, String> {
+    match api.get_acl() {
+        Some(acl) => match acl.authorize(api, method, request)? {
+            Access::Allowed => Ok(None),
+            Access::Blocked => Ok(Some(JsonValue::Null)),
+            Access::Forbidden => acl.deny(api, request).map(Some),
+        },
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Acl, Role, enforce};
+    use entities::Request;
+    use mock::{MockServer, json_ok};
+    use serde_json;
+    use serde_json::value::Value as JsonValue;
+    use toml;
+    use Telegram;
+
+    fn request(user_id: i64, chat_id: i64, chat_type: &str) -> Request {
+        serde_json::from_str(&format!(r#"{{"update_id":1,"message":{{"message_id":1,"date":0,"chat":{{"id":{},"type":"{}"}},"from":{{"is_bot":false,"id":{},"first_name":"Test"}},"text":"/find"}}}}"#, chat_id, chat_type, user_id)).unwrap()
+    }
+
+    #[test]
+    fn it_works() {
+        let server = MockServer::start().expect("Unable to start mock server");
+        server.respond("sendMessage", json_ok());
+        server.respond("getChatMember", serde_json::from_str(r#"{"ok":true,"result":{"status":"administrator","user":{"id":2,"is_bot":false,"first_name":"Test"}}}"#).unwrap());
+        let config: toml::Value = toml::from_str(r#"[ACL]
+BLOCKED_USERS = [13]
+BLOCKED_CHATS = [-666]
+ADMINS = [1]
+DENIED_REPLY = "Not allowed"
+
+[ACL.COMMANDS]
+find = "chat_admin"
+set_location = "admin"
+"#).unwrap();
+        let mut api = Telegram::new("test");
+        api.api_url = server.get_url().to_owned();
+        api.acl = Acl::from_config(&config).unwrap();
+
+        assert_eq!(api.get_acl().unwrap().get_role("find"), Role::ChatAdmin);
+        assert_eq!(api.get_acl().unwrap().get_role("help"), Role::Anyone);
+
+        //lists apply to every request, blocked ones are ignored without replying
+        assert_eq!(enforce(&api, None, &request(2, 2, "private")), Ok(None));
+        assert_eq!(enforce(&api, None, &request(13, 13, "private")), Ok(Some(JsonValue::Null)));
+        assert_eq!(enforce(&api, Some("help"), &request(2, -666, "group")), Ok(Some(JsonValue::Null)));
+        assert!(server.get_calls_to("sendMessage").is_empty());
+
+        //roles, denied ones get DENIED_REPLY
+        assert_eq!(enforce(&api, Some("find"), &request(2, -100, "supergroup")), Ok(None));
+        assert!(server.was_called("getChatMember", &[("chat_id", "-100"), ("user_id", "2")]));
+        assert_eq!(enforce(&api, Some("find"), &request(2, 2, "private")), Ok(Some(json_ok())));
+        assert!(server.was_called("sendMessage", &[("chat_id", "2"), ("text", "Not allowed")]));
+        assert_eq!(enforce(&api, Some("find"), &request(1, 1, "private")), Ok(None));
+        assert_eq!(enforce(&api, Some("set_location"), &request(2, -100, "supergroup")), Ok(Some(json_ok())));
+        assert_eq!(enforce(&api, Some("set_location"), &request(1, -100, "supergroup")), Ok(None));
+
+        //without ACL everything is allowed
+        api.acl = None;
+        assert_eq!(enforce(&api, Some("set_location"), &request(13, -666, "group")), Ok(None));
+
+        assert!(Acl::from_config(&toml::from_str("[ACL.COMMANDS]\nfind = \"root\"").unwrap()).is_err());
+        assert!(Acl::from_config(&toml::from_str("[ACL]\nADMINS = [\"pippo\"]").unwrap()).is_err());
+        assert!(Acl::from_config(&toml::from_str("").unwrap()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use serde_json;
+use serde_json::value::Value as JsonValue;
+
+use {FNV_OFFSET, fnv1a};
+
+/// session key used by FileCache::Session
+pub const SESSION_KEY: &'static str = "file_id_cache";
+
+/// #FileCache
+/// This object maps local files to the file_id Telegram assigned them on first upload,
+/// so that later sends of the same file don't need to upload it again.
+/// Entries are keyed by file path and contents hash, so an edited file is uploaded again.
+pub enum FileCache {
+    /// cache kept inside bot session, lost on server restart
+    Session(Arc<RwLock<HashMap<String, JsonValue>>>),
+    /// cache kept in a json file on disk
+    Disk(PathBuf),
+}
+
+impl FileCache {
+    /// builds a cache from FILE_CACHE config value: "session" or a path to a json file
+    pub fn from_config(value: &str, session: &Arc<RwLock<HashMap<String, JsonValue>>>) -> FileCache {
+        match value {
+            "session" => FileCache::Session(session.clone()),
+            path => FileCache::Disk(PathBuf::from(path)),
+        }
+    }
+
+    /// computes cache key for a local file
+    pub fn key(path: &str) -> Result<String, String> {
+        let mut file = File::open(path).map_err(|e| format!("Unable to open file {}: {:?}", path, e))?;
+        //keys must stay the same across builds, since Disk caches outlive them
+        let mut hash = FNV_OFFSET;
+        let mut buffer = [0u8; 8192];
+        loop {
+            match file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => { hash = fnv1a(hash, &buffer[..n]); },
+                Err(e) => { return Err(format!("Unable to read file {}: {:?}", path, e)); },
+            }
+        }
+        Ok(format!("{}#{:016x}", path, hash))
+    }
+
+    /// retrieves a cached file_id
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.load().ok()
+            .and_then(|map| map.get(key).and_then(|v| v.as_str()).map(String::from))
+    }
+
+    /// stores a file_id
+    pub fn set(&self, key: &str, file_id: &str) -> Result<(), String> {
+        self.update(|map| { map.insert(key.to_owned(), JsonValue::String(file_id.to_owned())); })
+    }
+
+    /// removes a file_id, e.g. when Telegram refuses it
+    pub fn remove(&self, key: &str) -> Result<(), String> {
+        self.update(|map| { map.remove(key); })
+    }
+
+    /// retrieves the whole cache
+    fn load(&self) -> Result<serde_json::Map<String, JsonValue>, String> {
+        match self {
+            &FileCache::Session(ref session) => (session.read().map_err(|e| format!("Error read locking session: {:?}", e)))
+                .map(|s| match s.get(SESSION_KEY) {
+                    Some(&JsonValue::Object(ref map)) => map.clone(),
+                    _ => serde_json::Map::new(),
+                }),
+            &FileCache::Disk(ref path) => match File::open(path) {
+                Ok(file) => serde_json::from_reader(file).map_err(|e| format!("Syntax error on file cache {:?}: {:?}", path, e)),
+                //missing file simply means empty cache
+                Err(_) => Ok(serde_json::Map::new()),
+            },
+        }
+    }
+
+    /// applies a change to the cache and persists it
+    fn update<F>(&self, f: F) -> Result<(), String>
+        where F: FnOnce(&mut serde_json::Map<String, JsonValue>)
+    {
+        match self {
+            &FileCache::Session(ref session) => (session.write().map_err(|e| format!("Error write locking session: {:?}", e)))
+                .map(|mut s| {
+                    let entry = s.entry(String::from(SESSION_KEY)).or_insert(JsonValue::Object(serde_json::Map::new()));
+                    if !entry.is_object() {
+                        *entry = JsonValue::Object(serde_json::Map::new());
+                    }
+                    if let &mut JsonValue::Object(ref mut map) = entry {
+                        f(map);
+                    }
+                }),
+            &FileCache::Disk(ref path) => {
+                let mut map = self.load()?;
+                f(&mut map);
+                (File::create(path).map_err(|e| format!("Unable to create file cache {:?}: {:?}", path, e)))
+                    .and_then(|mut file| file.write_all(JsonValue::Object(map).to_string().as_bytes())
+                        .map_err(|e| format!("Unable to write file cache {:?}: {:?}", path, e))
+                    )
+            },
+        }
+    }
+
+    /// checks if a failed call was refused because of its files, e.g. "wrong file identifier/HTTP URL specified",
+    /// other errors (chat not found, flood control...) say nothing about cached file_ids
+    pub fn is_file_error(res: &JsonValue) -> bool {
+        res["ok"].as_bool() != Some(true) && res["description"].as_str()
+            .map(|description| description.to_lowercase().contains("file"))
+            .unwrap_or(false)
+    }
+
+    /// extracts the file_id of the given field from a sent Message
+    pub fn extract_file_id<'a>(field: &str, message: &'a JsonValue) -> Option<&'a str> {
+        match message[field] {
+            //photos come in several sizes, the last one is the original
+            JsonValue::Array(ref sizes) => sizes.last().and_then(|size| size["file_id"].as_str()),
+            ref value => value["file_id"].as_str(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileCache, SESSION_KEY, serde_json};
+    use super::serde_json::value::Value as JsonValue;
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+
+    #[test]
+    fn it_works() {
+        let session = Arc::new(RwLock::new(HashMap::new()));
+        let cache = FileCache::from_config("session", &session);
+        let key = FileCache::key("Cargo.toml").unwrap();
+
+        assert_eq!(cache.get(&key), None);
+        cache.set(&key, "AgADBAADb6cxG").unwrap();
+        assert_eq!(cache.get(&key), Some(String::from("AgADBAADb6cxG")));
+        assert!(session.read().unwrap().contains_key(SESSION_KEY));
+        cache.remove(&key).unwrap();
+        assert_eq!(cache.get(&key), None);
+
+        let message: JsonValue = serde_json::from_str(r#"{"photo":[{"file_id":"small"},{"file_id":"big"}],"document":{"file_id":"doc"}}"#).unwrap();
+        assert_eq!(FileCache::extract_file_id("photo", &message), Some("big"));
+        assert_eq!(FileCache::extract_file_id("document", &message), Some("doc"));
+        assert_eq!(FileCache::extract_file_id("audio", &message), None);
+
+        assert!(FileCache::is_file_error(&serde_json::from_str(r#"{"ok":false,"error_code":400,"description":"Bad Request: wrong file identifier/HTTP URL specified"}"#).unwrap()));
+        assert!(!FileCache::is_file_error(&serde_json::from_str(r#"{"ok":false,"error_code":400,"description":"Bad Request: chat not found"}"#).unwrap()));
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use serde_json;
+use serde_json::value::Value as JsonValue;
+
+use entities::{CallbackQuery, InlineKeyboardButton, Request};
+use session::Session;
+use {FNV_OFFSET, Telegram, fnv1a};
+
+/// maximum callback_data size allowed by Telegram, in bytes
+pub const MAX_CALLBACK_DATA: usize = 64;
+/// separates prefix and inline payload in callback_data
+pub const INLINE_SEPARATOR: char = ':';
+/// separates prefix and session token in callback_data
+pub const TOKEN_SEPARATOR: char = '#';
+/// global session key prefix of payloads too big for callback_data
+pub const TOKEN_KEY_PREFIX: &'static str = "callback:";
+/// default lifetime of payloads stored in session, buttons older than this stop working
+pub const DEFAULT_TOKEN_TTL: u64 = 7 * 24 * 60 * 60;
+
+/// handles a callback query: receives the bot, the query and the decoded payload
+pub type CallbackHandler<B> = Box<dyn Fn(&B, &CallbackQuery, JsonValue) -> Result<JsonValue, String>>;
+
+/// #CallbackRouter
+/// Maps callback_data prefixes to handlers, with typed payloads.
+/// Payloads are json encoded after the prefix, like `vote:{"id":3}`, when they fit Telegram's 64 bytes,
+/// otherwise they're stored in bot's global session and callback_data only holds a short token, like `vote#1f2e3d4c5b6a7980`.
+/// Callback queries are answered automatically, so that clients stop showing progress.
+pub struct CallbackRouter<B> {
+    handlers: HashMap<String, CallbackHandler<B>>,
+    token_ttl: Duration,
+    auto_answer: bool,
+    expired_text: String,
+}
+
+impl<B> CallbackRouter<B> {
+    /// creates an empty router
+    pub fn new() -> CallbackRouter<B> {
+        CallbackRouter {
+            handlers: HashMap::new(),
+            token_ttl: Duration::from_secs(DEFAULT_TOKEN_TTL),
+            auto_answer: true,
+            expired_text: String::from("This button has expired"),
+        }
+    }
+
+    /// registers a handler for a prefix, payload is deserialized to the handler's type
+    pub fn on<T, F>(mut self, prefix: &str, handler: F) -> CallbackRouter<B>
+        where B: 'static,
+            T: DeserializeOwned + 'static,
+            F: Fn(&B, &CallbackQuery, T) -> Result<JsonValue, String> + 'static
+    {
+        let name = prefix.to_owned();
+        self.handlers.insert(prefix.to_owned(), Box::new(move |bot: &B, query: &CallbackQuery, payload: JsonValue| {
+            let value = serde_json::from_value(payload).map_err(|e| format!("Invalid payload for callback {}: {:?}", name, e))?;
+            handler(bot, query, value)
+        }));
+        self
+    }
+
+    /// sets how long payloads stored in session last
+    pub fn token_ttl(mut self, ttl: Duration) -> CallbackRouter<B> {
+        self.token_ttl = ttl;
+        self
+    }
+
+    /// sets if callback queries are answered automatically after handling them
+    pub fn auto_answer(mut self, auto_answer: bool) -> CallbackRouter<B> {
+        self.auto_answer = auto_answer;
+        self
+    }
+
+    /// sets the text shown when a button's payload expired
+    pub fn expired_text(mut self, text: &str) -> CallbackRouter<B> {
+        self.expired_text = text.to_owned();
+        self
+    }
+
+    /// encodes a payload into callback_data, storing it in session if too big
+    pub fn encode<T: Serialize>(&self, session: &Session, prefix: &str, payload: &T) -> Result<String, String> {
+        if prefix.contains(INLINE_SEPARATOR) || prefix.contains(TOKEN_SEPARATOR) {
+            return Err(format!("Invalid callback prefix {}", prefix));
+        }
+        let json = serde_json::to_string(payload).map_err(|e| format!("Unable to serialize callback payload: {:?}", e))?;
+
+        let data = format!("{}{}{}", prefix, INLINE_SEPARATOR, json);
+        if data.len() <= MAX_CALLBACK_DATA {
+            return Ok(data);
+        }
+
+        //same payload, same token, even after a Rust upgrade since tokens live in session
+        let token = format!("{:016x}", fnv1a(fnv1a(FNV_OFFSET, prefix.as_bytes()), json.as_bytes()));
+        let data = format!("{}{}{}", prefix, TOKEN_SEPARATOR, token);
+        if data.len() > MAX_CALLBACK_DATA {
+            return Err(format!("Callback prefix {} too long", prefix));
+        }
+        session.global().set_with_ttl(&format!("{}{}", TOKEN_KEY_PREFIX, token), &serde_json::from_str::<JsonValue>(&json).map_err(|e| format!("Unable to serialize callback payload: {:?}", e))?, self.token_ttl)?;
+        Ok(data)
+    }
+
+    /// creates an inline keyboard button carrying the encoded payload
+    pub fn button<T: Serialize>(&self, session: &Session, text: &str, prefix: &str, payload: &T) -> Result<InlineKeyboardButton, String> {
+        Ok(InlineKeyboardButton::callback(text, &self.encode(session, prefix, payload)?))
+    }
+
+    /// decodes callback_data into prefix and payload, None if the payload expired
+    pub fn decode(&self, session: &Session, data: &str) -> Result<Option<(String, JsonValue)>, String> {
+        match data.find(|c: char| c == INLINE_SEPARATOR || c == TOKEN_SEPARATOR) {
+            Some(pos) => {
+                let prefix = data[..pos].to_owned();
+                let rest = &data[pos + 1..];
+                if data[pos..].starts_with(INLINE_SEPARATOR) {
+                    serde_json::from_str(rest).map(|payload| Some((prefix, payload))).map_err(|e| format!("Invalid callback data {}: {:?}", data, e))
+                }
+                else {
+                    Ok(session.global().get::<JsonValue>(&format!("{}{}", TOKEN_KEY_PREFIX, rest))?.map(|payload| (prefix, payload)))
+                }
+            },
+            //plain data, without payload
+            None => Ok(Some((data.to_owned(), JsonValue::Null))),
+        }
+    }
+
+    /// routes a callback query to the handler registered for its prefix, then answers it
+    /// bots usually call it from Bot::dispatch, see parse_callback_query
+    pub fn handle(&self, bot: &B, api: &Telegram, session: &Session, query: &CallbackQuery) -> Result<JsonValue, String> {
+        let data = match query.get_data() {
+            &Some(ref d) => d,
+            &None => { return Err(String::from("Callback query without data")); },
+        };
+
+        //unknown prefixes, like stale keyboards of older bot versions, are treated as expired payloads
+        let res = match self.decode(session, data)?.and_then(|(prefix, payload)| self.handlers.get(&prefix).map(|handler| (handler, payload))) {
+            Some((handler, payload)) => handler(bot, query, payload),
+            None => {
+                if self.auto_answer {
+                    let _ = api.answer_callback_query(query.get_id(), Some(&self.expired_text), Some(true), None, None);
+                }
+                return Ok(JsonValue::Null);
+            },
+        };
+
+        //answer even on errors, or the client keeps waiting,
+        //a failed answer only leaves the client spinning, so it never replaces the handler's result
+        if self.auto_answer {
+            let _ = api.answer_callback_query(query.get_id(), None, None, None, None);
+        }
+        res
+    }
+
+    /// routes the callback query of a Request, see handle
+    pub fn handle_request(&self, bot: &B, api: &Telegram, session: &Session, request: &Request) -> Result<JsonValue, String> {
+        match request.get_callback_query() {
+            &Some(ref query) => self.handle(bot, api, session, query),
+            &None => Err(String::from("Not a callback query")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CallbackRouter, MAX_CALLBACK_DATA};
+    use entities::CallbackQuery;
+    use mock::{MockServer, json_error, json_ok};
+    use serde_json;
+    use serde_json::value::Value as JsonValue;
+    use session::Session;
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+    use Telegram;
+
+    #[derive(Serialize, Deserialize)]
+    struct Vote {
+        id: u64,
+        comment: String,
+    }
+
+    struct TestBot;
+
+    fn query(data: &str) -> CallbackQuery {
+        serde_json::from_str(&format!(r#"{{"id":"42","from":{{"id":2,"is_bot":false,"first_name":"Test"}},"data":"{}"}}"#, data.replace('"', "\\\""))).unwrap()
+    }
+
+    #[test]
+    fn it_works() {
+        let server = MockServer::start().expect("Unable to start mock server");
+        server.respond("answerCallbackQuery", json_ok());
+        let mut api = Telegram::new("test");
+        api.api_url = server.get_url().to_owned();
+        let session = Session::new(&Arc::new(RwLock::new(HashMap::new())));
+
+        let router = CallbackRouter::new()
+            .on("vote", |_bot: &TestBot, _query, vote: Vote| Ok(JsonValue::from(format!("{} {}", vote.id, vote.comment))));
+
+        //small payloads are inline
+        let data = router.encode(&session, "vote", &Vote { id: 3, comment: String::from("ok") }).unwrap();
+        assert_eq!(data, r#"vote:{"id":3,"comment":"ok"}"#);
+        assert_eq!(router.handle(&TestBot, &api, &session, &query(&data)), Ok(JsonValue::from("3 ok")));
+        assert!(server.was_called("answerCallbackQuery", &[("callback_query_id", "42")]));
+
+        //big ones are kept in session
+        let long = Vote { id: 4, comment: "a".repeat(100) };
+        let data = router.encode(&session, "vote", &long).unwrap();
+        assert!(data.len() <= MAX_CALLBACK_DATA);
+        //tokens are stable across builds
+        assert_eq!(data, "vote#bc192ef8a01a0bbd");
+        assert_eq!(router.handle(&TestBot, &api, &session, &query(&data)), Ok(JsonValue::from(format!("4 {}", "a".repeat(100)))));
+
+        //expired payloads are notified
+        server.clear();
+        assert_eq!(router.handle(&TestBot, &api, &session, &query("vote#0000000000000000")), Ok(JsonValue::Null));
+        assert!(server.was_called("answerCallbackQuery", &[("text", "This button has expired"), ("show_alert", "true")]));
+
+        //so are stale buttons of unknown prefixes
+        server.clear();
+        assert_eq!(router.handle(&TestBot, &api, &session, &query("other")), Ok(JsonValue::Null));
+        assert!(server.was_called("answerCallbackQuery", &[("text", "This button has expired"), ("show_alert", "true")]));
+        //a failed answer doesn't fail the update
+        server.respond("answerCallbackQuery", json_error(400, "Bad Request: query is too old"));
+        assert_eq!(router.handle(&TestBot, &api, &session, &query("vote#0000000000000000")), Ok(JsonValue::Null));
+
+        assert!(router.handle(&TestBot, &api, &session, &query(r#"vote:{"id":"x"}"#)).is_err());
+        assert!(router.encode(&session, "vo:te", &1).is_err());
+    }
+}
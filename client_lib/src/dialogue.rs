@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use serde_json;
+use serde_json::value::Value as JsonValue;
+
+use toml::Value as TomlValue;
+
+use entities::{ForceReply, Message, ReplyMarkup};
+use session::Session;
+use Telegram;
+
+/// chat session key holding the active dialogue
+pub const DIALOGUE_KEY: &'static str = "dialogue";
+
+/// what a step handler asks the dialogue to do next
+pub enum Transition {
+    /// move to the given step, sending its prompt
+    Next(String),
+    /// stay on current step, sending the given text, or the step prompt again
+    Retry(Option<String>),
+    /// close the dialogue, optionally sending a last message
+    End(Option<String>),
+}
+
+/// handles the answer to a step: receives the bot, the message and dialogue data,
+/// a json object where answers can be collected until the dialogue ends
+pub type StepHandler<B> = Box<dyn Fn(&B, &Message, &mut JsonValue) -> Result<Transition, String>>;
+
+struct Step<B> {
+    prompt: String,
+    handler: StepHandler<B>,
+}
+
+/// active dialogue, as persisted in chat session
+#[derive(Debug, Serialize, Deserialize)]
+struct DialogueState {
+    name: String,
+    step: String,
+    data: JsonValue,
+}
+
+/// #Dialogue
+/// A multi-step conversation: every step sends a prompt, with a ForceReply,
+/// and handles the answer, choosing the next step.
+pub struct Dialogue<B> {
+    name: String,
+    initial: String,
+    steps: HashMap<String, Step<B>>,
+    timeout: Option<Duration>,
+}
+
+impl<B> Dialogue<B> {
+    /// creates a dialogue, starting from the given step
+    pub fn new(name: &str, initial: &str) -> Dialogue<B> {
+        Dialogue {
+            name: name.to_owned(),
+            initial: initial.to_owned(),
+            steps: HashMap::new(),
+            timeout: None,
+        }
+    }
+
+    /// adds a step
+    pub fn step<F>(mut self, name: &str, prompt: &str, handler: F) -> Dialogue<B>
+        where F: Fn(&B, &Message, &mut JsonValue) -> Result<Transition, String> + 'static
+    {
+        self.steps.insert(name.to_owned(), Step {
+            prompt: prompt.to_owned(),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// forgets the dialogue if no answer arrives within timeout
+    pub fn timeout(mut self, timeout: Duration) -> Dialogue<B> {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+/// #Dialogues
+/// Every dialogue a bot can hold, with the per-chat active one kept in session.
+/// Bots plug it into Bot::parse_dialogue, so that non-command messages reach the active step.
+/// Cancel commands addressed to another bot, like /cancel@OtherBot in groups, are ignored,
+/// using BOT_NAME config value to recognize the bot's own ones.
+pub struct Dialogues<B> {
+    bot_name: Option<String>,
+    dialogues: HashMap<String, Dialogue<B>>,
+    cancel_commands: Vec<String>,
+    cancel_message: String,
+}
+
+impl<B> Dialogues<B> {
+    /// creates an empty set of dialogues, cancelled by /cancel
+    pub fn new() -> Dialogues<B> {
+        Dialogues {
+            bot_name: None,
+            dialogues: HashMap::new(),
+            cancel_commands: vec![String::from("/cancel")],
+            cancel_message: String::from("Cancelled"),
+        }
+    }
+
+    /// creates an empty set of dialogues, reading bot name from BOT_NAME config value, if present
+    pub fn from_config(config: &Arc<RwLock<TomlValue>>) -> Dialogues<B> {
+        let dialogues = Dialogues::new();
+        match config.read().ok().and_then(|c| c.get("BOT_NAME").and_then(|n| n.as_str()).map(String::from)) {
+            Some(name) => dialogues.bot_name(&name),
+            None => dialogues,
+        }
+    }
+
+    /// sets bot name, with or without leading @
+    pub fn bot_name(mut self, name: &str) -> Dialogues<B> {
+        self.bot_name = Some(name.trim_start_matches('@').to_owned());
+        self
+    }
+
+    /// adds a dialogue
+    pub fn add(mut self, dialogue: Dialogue<B>) -> Dialogues<B> {
+        self.dialogues.insert(dialogue.name.clone(), dialogue);
+        self
+    }
+
+    /// sets commands closing the active dialogue, and the message confirming it
+    pub fn cancel(mut self, commands: &[&str], message: &str) -> Dialogues<B> {
+        self.cancel_commands = commands.iter().map(|c| String::from(*c)).collect();
+        self.cancel_message = message.to_owned();
+        self
+    }
+
+    /// starts a dialogue in a chat, replacing the active one, and sends the first prompt
+    /// replying to reply_id, usually the command starting it: in groups the prompt targets its sender only,
+    /// without it every member is asked to reply
+    pub fn start(&self, api: &Telegram, session: &Session, chat_id: i64, reply_id: Option<&str>, name: &str) -> Result<JsonValue, String> {
+        let dialogue = self.dialogues.get(name).ok_or(format!("Dialogue {} not found", name))?;
+        let state = DialogueState {
+            name: name.to_owned(),
+            step: dialogue.initial.clone(),
+            data: JsonValue::Object(serde_json::Map::new()),
+        };
+        self.enter(api, session, chat_id, reply_id, dialogue, state, None)
+    }
+
+    /// returns name and step of the active dialogue in a chat
+    pub fn get_active(&self, session: &Session, chat_id: i64) -> Result<Option<(String, String)>, String> {
+        Ok(session.chat(chat_id).get::<DialogueState>(DIALOGUE_KEY)?.map(|state| (state.name, state.step)))
+    }
+
+    /// routes a message to the active dialogue of its chat
+    /// returns None if there's no active dialogue, or the message is a command other than cancel ones
+    pub fn handle(&self, bot: &B, api: &Telegram, session: &Session, message: &Message) -> Option<Result<JsonValue, String>> {
+        let chat_id = message.get_chat().get_id();
+        let mut state = match session.chat(chat_id).get::<DialogueState>(DIALOGUE_KEY) {
+            Ok(Some(s)) => s,
+            Ok(None) => { return None; },
+            Err(e) => { return Some(Err(e)); },
+        };
+
+        match message.get_command() {
+            Some((command, _)) => {
+                //commands can be addressed to a bot, like /cancel@bot, the ones for other bots are left alone
+                let mut parts = command.splitn(2, '@');
+                let command = parts.next().unwrap_or("");
+                match (parts.next(), &self.bot_name) {
+                    (Some(target), &Some(ref bot_name)) if !target.eq_ignore_ascii_case(bot_name) => { return None; },
+                    _ => {},
+                }
+                if self.cancel_commands.iter().any(|c| c == command) {
+                    return Some(session.chat(chat_id).remove(DIALOGUE_KEY)
+                        .and_then(|_| api.send_message(&chat_id.to_string(), &self.cancel_message, None, None, None, None)));
+                }
+                return None;
+            },
+            None => {},
+        }
+
+        let dialogue = match self.dialogues.get(&state.name) {
+            Some(d) => d,
+            //dialogue removed by a bot update, forget it
+            None => { return Some(session.chat(chat_id).remove(DIALOGUE_KEY).map(|_| JsonValue::Null)); },
+        };
+        let step = match dialogue.steps.get(&state.step) {
+            Some(s) => s,
+            None => { return Some(Err(format!("Step {} not found in dialogue {}", state.step, state.name))); },
+        };
+
+        //prompts reply to the answer, so that in groups they target its sender
+        let reply_id = message.get_message_id().to_string();
+        Some((step.handler)(bot, message, &mut state.data).and_then(|transition| match transition {
+            Transition::Next(next) => {
+                state.step = next;
+                self.enter(api, session, chat_id, Some(&reply_id), dialogue, state, None)
+            },
+            Transition::Retry(text) => self.enter(api, session, chat_id, Some(&reply_id), dialogue, state, text),
+            Transition::End(text) => {
+                session.chat(chat_id).remove(DIALOGUE_KEY)?;
+                match text {
+                    Some(t) => api.send_message(&chat_id.to_string(), &t, None, None, None, None),
+                    None => Ok(JsonValue::Null),
+                }
+            },
+        }))
+    }
+
+    /// saves the state and sends the step prompt, or the given text
+    /// a selective ForceReply reaches only the sender of the replied message, so it's used only when replying
+    fn enter(&self, api: &Telegram, session: &Session, chat_id: i64, reply_id: Option<&str>, dialogue: &Dialogue<B>, state: DialogueState, text: Option<String>) -> Result<JsonValue, String> {
+        let step = dialogue.steps.get(&state.step).ok_or(format!("Step {} not found in dialogue {}", state.step, state.name))?;
+        match dialogue.timeout {
+            Some(timeout) => session.chat(chat_id).set_with_ttl(DIALOGUE_KEY, &state, timeout)?,
+            None => session.chat(chat_id).set(DIALOGUE_KEY, &state)?,
+        }
+        api.send_message(&chat_id.to_string(), &text.unwrap_or(step.prompt.clone()), reply_id, None, None, Some(ReplyMarkup::ForceReply(ForceReply::new(reply_id.is_some()))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dialogue, Dialogues, Transition};
+    use entities::Message;
+    use mock::{MockServer, json_ok};
+    use serde_json;
+    use serde_json::value::Value as JsonValue;
+    use session::Session;
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+    use Telegram;
+
+    struct TestBot {
+        min_age: u64,
+    }
+
+    fn message(text: &str) -> Message {
+        //commands are marked by Telegram
+        let entities = if text.starts_with('/') { format!(r#"{{"type":"bot_command","offset":0,"length":{}}}"#, text.split(' ').next().unwrap().len()) } else { String::new() };
+        serde_json::from_str(&format!(r#"{{"message_id":1,"from":{{"id":2,"is_bot":false,"first_name":"Test"}},"date":0,"chat":{{"id":1111111,"type":"private"}},"text":"{}","entities":[{}]}}"#, text, entities)).unwrap()
+    }
+
+    #[test]
+    fn it_works() {
+        let server = MockServer::start().expect("Unable to start mock server");
+        server.respond("sendMessage", json_ok());
+        let mut api = Telegram::new("test");
+        api.api_url = server.get_url().to_owned();
+        let session = Session::new(&Arc::new(RwLock::new(HashMap::new())));
+        let bot = TestBot { min_age: 18 };
+
+        let dialogues = Dialogues::new().bot_name("@Test_Bot").add(Dialogue::new("signup", "name")
+            .step("name", "What's your name?", |_bot: &TestBot, msg, data| {
+                data["name"] = JsonValue::from(msg.get_text().clone().unwrap_or_default());
+                Ok(Transition::Next(String::from("age")))
+            })
+            .step("age", "How old are you?", |bot: &TestBot, msg, data| {
+                match msg.get_text().as_ref().and_then(|t| t.parse::<u64>().ok()) {
+                    Some(age) if age >= bot.min_age => Ok(Transition::End(Some(format!("Welcome {}", data["name"].as_str().unwrap_or(""))))),
+                    Some(_) => Ok(Transition::End(Some(String::from("Too young")))),
+                    None => Ok(Transition::Retry(Some(String::from("Please send a number")))),
+                }
+            }));
+
+        //no active dialogue
+        assert!(dialogues.handle(&bot, &api, &session, &message("pippo")).is_none());
+
+        dialogues.start(&api, &session, 1111111, None, "signup").unwrap();
+        assert!(server.was_called("sendMessage", &[("text", "What's your name?")]));
+        dialogues.handle(&bot, &api, &session, &message("pippo")).unwrap().unwrap();
+        assert!(server.was_called("sendMessage", &[("text", "How old are you?"), ("reply_to_message_id", "1")]));
+        assert_eq!(dialogues.get_active(&session, 1111111).unwrap(), Some((String::from("signup"), String::from("age"))));
+        //other commands aren't handled by dialogues
+        assert!(dialogues.handle(&bot, &api, &session, &message("/help")).is_none());
+        dialogues.handle(&bot, &api, &session, &message("many")).unwrap().unwrap();
+        assert!(server.was_called("sendMessage", &[("text", "Please send a number")]));
+        dialogues.handle(&bot, &api, &session, &message("30")).unwrap().unwrap();
+        assert!(server.was_called("sendMessage", &[("text", "Welcome pippo")]));
+        assert_eq!(dialogues.get_active(&session, 1111111).unwrap(), None);
+
+        dialogues.start(&api, &session, 1111111, Some("1"), "signup").unwrap();
+        //cancel commands for other bots don't close it
+        assert!(dialogues.handle(&bot, &api, &session, &message("/cancel@OtherBot")).is_none());
+        assert_eq!(dialogues.get_active(&session, 1111111).unwrap(), Some((String::from("signup"), String::from("name"))));
+        dialogues.handle(&bot, &api, &session, &message("/cancel@test_bot")).unwrap().unwrap();
+        assert!(server.was_called("sendMessage", &[("text", "Cancelled")]));
+        assert_eq!(dialogues.get_active(&session, 1111111).unwrap(), None);
+
+        assert!(dialogues.start(&api, &session, 1111111, None, "missing").is_err());
+    }
+}
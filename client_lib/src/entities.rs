@@ -1,4 +1,5 @@
 use std::fmt;
+use std::io::Read;
 
 /// #RequestType
 /// This object represents a Telegram Request.
@@ -68,10 +69,32 @@ impl Request {
     pub fn get_callback_query(&self) -> &Option<CallbackQuery> {
         &self.callback_query
     }
+
+    /// returns the chat the Request comes from, None for inline queries and results
+    pub fn get_chat(&self) -> Option<&Chat> {
+        match (&self.message, &self.edited_message, &self.callback_query) {
+            (&Some(ref m), _, _) | (_, &Some(ref m), _) => Some(&m.chat),
+            (_, _, &Some(ref q)) => q.message.as_ref().map(|m| &m.chat),
+            _ => None,
+        }
+    }
+
+    /// returns the user who sent the Request, whatever its type
+    pub fn get_from(&self) -> Option<&User> {
+        match self.get_type() {
+            Ok(RequestType::Message) => self.message.as_ref().map(|m| &m.from),
+            Ok(RequestType::EditedMesage) => self.edited_message.as_ref().map(|m| &m.from),
+            Ok(RequestType::InlineQuery) => self.inline_query.as_ref().map(|q| &q.from),
+            Ok(RequestType::ChosenInlineResult) => self.chosen_inline_result.as_ref().map(|r| &r.from),
+            Ok(RequestType::CallbackQuery) => self.callback_query.as_ref().map(|q| &q.from),
+            Err(_) => None,
+        }
+    }
 }
 
 /// #RequestType
 /// This object represents the type of a Telegram Request.
+#[derive(Debug, PartialEq)]
 pub enum RequestType {
     /// see Message
     Message,
@@ -105,6 +128,16 @@ impl User {
     pub fn get_id(&self) -> i64 {
         self.id
     }
+
+    /// returns first_name
+    pub fn get_first_name(&self) -> &str {
+        &self.first_name
+    }
+
+    /// returns username
+    pub fn get_username(&self) -> &Option<String> {
+        &self.username
+    }
 }
 
 /// #Chat
@@ -141,6 +174,11 @@ impl Chat {
     pub fn get_id(&self) -> i64 {
         self.id
     }
+
+    /// returns type: private, group, supergroup or channel
+    pub fn get_type(&self) -> &str {
+        &self.type_
+    }
 }
 
 /// #Message
@@ -172,7 +210,7 @@ pub struct Message {
     #[serde(default)]
     entities: Option<Vec<MessageEntity>>,
     #[serde(default)]
-    caption_entities: Option<MessageEntity>,
+    caption_entities: Option<Vec<MessageEntity>>,
     #[serde(default)]
     audio: Option<Audio>,
     #[serde(default)]
@@ -224,6 +262,11 @@ pub struct Message {
 }
 
 impl Message {
+    /// returns message_id
+    pub fn get_message_id(&self) -> u64 {
+        self.message_id
+    }
+
     /// returns from
     pub fn get_from(&self) -> &User {
         &self.from
@@ -234,6 +277,21 @@ impl Message {
         &self.chat
     }
 
+    /// returns date, as unix time
+    pub fn get_date(&self) -> u64 {
+        self.date
+    }
+
+    /// returns edit_date, as unix time
+    pub fn get_edit_date(&self) -> Option<u64> {
+        self.edit_date
+    }
+
+    /// returns reply_to_message
+    pub fn get_reply_to_message(&self) -> &Option<Box<Message>> {
+        &self.reply_to_message
+    }
+
     /// returns text
     pub fn get_text(&self) -> &Option<String> {
         &self.text
@@ -243,8 +301,107 @@ impl Message {
     pub fn get_location(&self) -> &Option<Location> {
         &self.location
     }
+
+    /// returns entities
+    pub fn get_entities(&self) -> &Option<Vec<MessageEntity>> {
+        &self.entities
+    }
+
+    /// returns caption
+    pub fn get_caption(&self) -> &Option<String> {
+        &self.caption
+    }
+
+    /// returns caption_entities
+    pub fn get_caption_entities(&self) -> &Option<Vec<MessageEntity>> {
+        &self.caption_entities
+    }
+
+    /// returns the text of every entity of the given type, in order
+    pub fn get_entities_text(&self, type_: &str) -> Vec<&str> {
+        match (&self.text, &self.entities) {
+            (&Some(ref text), &Some(ref entities)) => entities.iter()
+                .filter(|e| e.type_ == type_)
+                .filter_map(|e| e.extract(text))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// returns the command the text starts with, like "/start@Bot", and the text after it
+    /// only commands marked by Telegram count, so pasted paths like /usr/bin aren't commands
+    pub fn get_command(&self) -> Option<(&str, &str)> {
+        self.get_leading_entity(ENTITY_BOT_COMMAND)
+    }
+
+    /// returns the entity of the given type the text starts with, and the text after it
+    pub fn get_leading_entity(&self, type_: &str) -> Option<(&str, &str)> {
+        match (&self.text, &self.entities) {
+            (&Some(ref text), &Some(ref entities)) => entities.iter()
+                .find(|e| e.type_ == type_ && e.offset == 0)
+                .and_then(|e| e.extract(text))
+                .map(|command| (command, &text[command.len()..])),
+            _ => None,
+        }
+    }
+
+    /// returns every command in the text
+    pub fn get_commands(&self) -> Vec<&str> {
+        self.get_entities_text(ENTITY_BOT_COMMAND)
+    }
+
+    /// returns every @username mention in the text
+    pub fn get_mentions(&self) -> Vec<&str> {
+        self.get_entities_text(ENTITY_MENTION)
+    }
+
+    /// returns every hashtag in the text
+    pub fn get_hashtags(&self) -> Vec<&str> {
+        self.get_entities_text(ENTITY_HASHTAG)
+    }
+
+    /// returns every URL in the text, both written ones and text links
+    pub fn get_urls(&self) -> Vec<&str> {
+        match (&self.text, &self.entities) {
+            (&Some(ref text), &Some(ref entities)) => entities.iter()
+                .filter_map(|e| match e.type_.as_str() {
+                    ENTITY_URL => e.extract(text),
+                    ENTITY_TEXT_LINK => e.url.as_ref().map(|u| u.as_str()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// returns users mentioned without username, with the mentioning text
+    pub fn get_text_mentions(&self) -> Vec<(&str, &User)> {
+        match (&self.text, &self.entities) {
+            (&Some(ref text), &Some(ref entities)) => entities.iter()
+                .filter(|e| e.type_ == ENTITY_TEXT_MENTION)
+                .filter_map(|e| match (e.extract(text), &e.user) {
+                    (Some(t), &Some(ref user)) => Some((t, user)),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
 }
 
+/// MessageEntity type of /commands
+pub const ENTITY_BOT_COMMAND: &'static str = "bot_command";
+/// MessageEntity type of @username mentions
+pub const ENTITY_MENTION: &'static str = "mention";
+/// MessageEntity type of #hashtags
+pub const ENTITY_HASHTAG: &'static str = "hashtag";
+/// MessageEntity type of written URLs
+pub const ENTITY_URL: &'static str = "url";
+/// MessageEntity type of clickable text URLs
+pub const ENTITY_TEXT_LINK: &'static str = "text_link";
+/// MessageEntity type of mentions of users without username
+pub const ENTITY_TEXT_MENTION: &'static str = "text_mention";
+
 /// #MessageEntity
 /// This object represents one special entity in a text message. For example, hashtags, usernames, URLs, etc.
 #[derive(Debug, Serialize, Deserialize)]
@@ -259,6 +416,60 @@ pub struct MessageEntity {
     user: Option<User>,
 }
 
+impl MessageEntity {
+    /// returns type
+    pub fn get_type(&self) -> &str {
+        &self.type_
+    }
+
+    /// returns offset, in UTF-16 code units
+    pub fn get_offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// returns length, in UTF-16 code units
+    pub fn get_length(&self) -> u64 {
+        self.length
+    }
+
+    /// returns url, for text_link entities
+    pub fn get_url(&self) -> &Option<String> {
+        &self.url
+    }
+
+    /// returns user, for text_mention entities
+    pub fn get_user(&self) -> &Option<User> {
+        &self.user
+    }
+
+    /// returns the part of text the entity refers to
+    /// Telegram counts offset and length in UTF-16 code units, so chars outside the BMP, like emojis, count twice
+    /// returns None if the entity doesn't fit the text
+    pub fn extract<'a>(&self, text: &'a str) -> Option<&'a str> {
+        let end = self.offset + self.length;
+        let mut units = 0u64;
+        let mut start_byte = None;
+        for (i, c) in text.char_indices() {
+            if units == self.offset {
+                start_byte = Some(i);
+            }
+            if units == end {
+                return start_byte.map(|s| &text[s..i]);
+            }
+            units += c.len_utf16() as u64;
+        }
+        if units == self.offset {
+            start_byte = Some(text.len());
+        }
+        if units == end {
+            start_byte.map(|s| &text[s..])
+        }
+        else {
+            None
+        }
+    }
+}
+
 /// #PhotoSize
 /// This object represents one size of a photo or a file / sticker thumbnail.
 #[derive(Debug, Serialize, Deserialize)]
@@ -454,25 +665,73 @@ pub struct InlineKeyboardMarkup {
     inline_keyboard: Vec<Vec<InlineKeyboardButton>>,
 }
 
+impl InlineKeyboardMarkup {
+    /// creates an inline keyboard from its rows of buttons
+    pub fn new(inline_keyboard: Vec<Vec<InlineKeyboardButton>>) -> InlineKeyboardMarkup {
+        InlineKeyboardMarkup {
+            inline_keyboard: inline_keyboard,
+        }
+    }
+}
+
 /// #InlineKeyboardButton
 /// This object represents one button of an inline keyboard. You must use exactly one of the optional fields.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InlineKeyboardButton {
     text: String,
-    #[serde(default)]
+    //only one field can be sent, so missing ones aren't serialized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     url: Option<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     callback_data: Option<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     switch_inline_query: Option<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     switch_inline_query_current_chat: Option<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     callback_game: Option<CallbackGame>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pay: Option<bool>,
 }
 
+impl InlineKeyboardButton {
+    /// creates a button opening an URL
+    pub fn url(text: &str, url: &str) -> InlineKeyboardButton {
+        InlineKeyboardButton {
+            text: text.to_owned(),
+            url: Some(url.to_owned()),
+            callback_data: None,
+            switch_inline_query: None,
+            switch_inline_query_current_chat: None,
+            callback_game: None,
+            pay: None,
+        }
+    }
+
+    /// creates a button sending a callback query with the given data, up to 64 bytes
+    pub fn callback(text: &str, data: &str) -> InlineKeyboardButton {
+        InlineKeyboardButton {
+            text: text.to_owned(),
+            url: None,
+            callback_data: Some(data.to_owned()),
+            switch_inline_query: None,
+            switch_inline_query_current_chat: None,
+            callback_game: None,
+            pay: None,
+        }
+    }
+
+    /// returns text
+    pub fn get_text(&self) -> &str {
+        &self.text
+    }
+
+    /// returns callback_data
+    pub fn get_callback_data(&self) -> &Option<String> {
+        &self.callback_data
+    }
+}
+
 /// #CallbackQuery
 /// This object represents an incoming callback query from a callback button in an inline keyboard.
 /// If the button that originated the query was attached to a message sent by the bot,
@@ -496,6 +755,33 @@ pub struct CallbackQuery {
     game_short_name: Option<String>,
 }
 
+impl CallbackQuery {
+    /// returns id
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    /// returns from
+    pub fn get_from(&self) -> &User {
+        &self.from
+    }
+
+    /// returns message
+    pub fn get_message(&self) -> &Option<Box<Message>> {
+        &self.message
+    }
+
+    /// returns inline_message_id
+    pub fn get_inline_message_id(&self) -> &Option<String> {
+        &self.inline_message_id
+    }
+
+    /// returns data
+    pub fn get_data(&self) -> &Option<String> {
+        &self.data
+    }
+}
+
 /// #ForceReply
 /// Upon receiving a message with this object, Telegram clients will display a reply interface
 /// to the user (act as if the user has selected the bot‘s message and tapped ’Reply').
@@ -508,6 +794,16 @@ pub struct ForceReply {
     selective: Option<bool>,
 }
 
+impl ForceReply {
+    /// creates a ForceReply, selective targets only mentioned users and the replied message sender
+    pub fn new(selective: bool) -> ForceReply {
+        ForceReply {
+            force_reply: true,
+            selective: if selective { Some(true) } else { None },
+        }
+    }
+}
+
 /// #ChatPhoto
 /// This object represents a chat photo.
 #[derive(Debug, Serialize, Deserialize)]
@@ -620,6 +916,24 @@ pub enum InputFile {
     Url(String),
     /// use File when uploading a local file
     File(String),
+    /// use Bytes when uploading an in-memory file
+    Bytes {
+        /// file name shown by Telegram clients
+        name: String,
+        /// file MIME type, if known
+        mime: Option<String>,
+        /// file contents
+        data: Vec<u8>,
+    },
+    /// use Reader when uploading a file from a stream
+    Reader {
+        /// file name shown by Telegram clients
+        name: String,
+        /// file MIME type, if known
+        mime: Option<String>,
+        /// file contents source
+        reader: Box<dyn Read + Send>,
+    },
 }
 
 /// #Sticker
@@ -1620,7 +1934,7 @@ pub enum ReplyMarkup {
 mod tests {
     extern crate serde_json;
 
-    use super::{Request, ParseMode, ChatAction};
+    use super::{Request, ParseMode, ChatAction, Message};
 
     #[test]
     fn it_works() {
@@ -2006,4 +2320,34 @@ r#"{
         serde_json::from_str::<ParseMode>("\"Markdown\"").unwrap();
         serde_json::from_str::<ChatAction>("\"typing\"").unwrap();
     }
+
+    #[test]
+    fn message_entities() {
+        //emoji takes 2 UTF-16 code units
+        let msg: Message = serde_json::from_str(r#"{
+  "message_id":1,
+  "date":1441645532,
+  "chat":{"id":1111111,"type":"private"},
+  "from":{"is_bot":false,"id":1111111,"first_name":"Test"},
+  "text":"/start@Bot 😀 #tag @pippo https://rust-lang.org link",
+  "entities":[
+    {"type":"bot_command","offset":0,"length":10},
+    {"type":"hashtag","offset":14,"length":4},
+    {"type":"mention","offset":19,"length":6},
+    {"type":"url","offset":26,"length":21},
+    {"type":"text_link","offset":48,"length":4,"url":"https://example.com"},
+    {"type":"hashtag","offset":50,"length":10}
+  ]
+}"#).unwrap();
+
+        assert_eq!(msg.get_command(), Some(("/start@Bot", " 😀 #tag @pippo https://rust-lang.org link")));
+        assert_eq!(msg.get_hashtags(), vec!["#tag"]);
+        assert_eq!(msg.get_mentions(), vec!["@pippo"]);
+        assert_eq!(msg.get_urls(), vec!["https://rust-lang.org", "https://example.com"]);
+        assert!(msg.get_text_mentions().is_empty());
+
+        //a pasted path isn't a command
+        let msg: Message = serde_json::from_str(r#"{"message_id":1,"date":0,"chat":{"id":1,"type":"private"},"from":{"is_bot":false,"id":1,"first_name":"Test"},"text":"/usr/bin"}"#).unwrap();
+        assert_eq!(msg.get_command(), None);
+    }
 }
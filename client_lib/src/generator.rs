@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, RwLock};
+
+use rand::{Rng, SeedableRng, StdRng};
+
+use serde_json::value::Value as JsonValue;
+
+use toml::Value as TomlValue;
+
+use entities::Request;
+use router::{Args, Router};
+use session::{Scope, Session};
+use {Bot, Telegram};
+
+/// bot config section holding generator settings and commands
+pub const GENERATOR_SECTION: &'static str = "GENERATOR";
+/// prefix of chat session keys holding recent choices, followed by table name
+pub const RECENT_PREFIX: &'static str = "generator:";
+/// maximum nesting of table references, stops endlessly recursive grammars
+const MAX_DEPTH: usize = 16;
+
+/// a piece of template
+#[derive(Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Table(String),
+    Args,
+    User,
+}
+
+/// a table entry, with its weight
+#[derive(Debug)]
+struct Choice {
+    parts: Vec<Part>,
+    weight: u64,
+}
+
+/// #Generator
+/// Grammar based text generator, configured in the [GENERATOR] section of bot's toml.
+/// Every command has a TEMPLATE, templates reference word tables, other top-level sections of bot's toml, as {TableName},
+/// and table entries can reference other tables in turn. {args} is replaced by command arguments and {user} by sender's name.
+/// Entries are picked at random, by WEIGHT, and the last AVOID_REPEATS choices of every table are avoided in the same chat.
+/// SEED is meant for testing only: every generate call starts again from it, so a command always gives the same text, AVOID_REPEATS aside.
+#[derive(Debug)]
+pub struct Generator {
+    commands: HashMap<String, Vec<Choice>>,
+    tables: HashMap<String, Vec<Choice>>,
+    avoid_repeats: usize,
+    seed: Option<usize>,
+}
+
+impl Generator {
+    /// reads commands and every table they reference, None if there's no GENERATOR section
+    pub fn from_config(config: &TomlValue) -> Result<Option<Generator>, String> {
+        let section = match config.get(GENERATOR_SECTION) {
+            Some(s) => s,
+            None => { return Ok(None); },
+        };
+
+        let mut generator = Generator {
+            commands: HashMap::new(),
+            tables: HashMap::new(),
+            avoid_repeats: match section.get("AVOID_REPEATS") {
+                Some(value) => value.as_integer().and_then(|n| if n >= 0 { Some(n as usize) } else { None })
+                    .ok_or(String::from("Error interpreting GENERATOR.AVOID_REPEATS config value"))?,
+                None => 0,
+            },
+            seed: match section.get("SEED") {
+                Some(value) => Some(value.as_integer().ok_or(String::from("Error interpreting GENERATOR.SEED config value"))? as usize),
+                None => None,
+            },
+        };
+
+        let mut pending = Vec::new();
+        match section.get("COMMANDS") {
+            Some(value) => {
+                let commands = value.as_table().ok_or(String::from("Error interpreting GENERATOR.COMMANDS config value"))?;
+                for (name, command) in commands {
+                    let key = format!("GENERATOR.COMMANDS.{}.TEMPLATE", name);
+                    let templates = parse_choices(command.get("TEMPLATE").ok_or(format!("Missing {} config value", key))?, &key)?;
+                    pending.extend(references(&templates));
+                    generator.commands.insert(name.clone(), templates);
+                }
+            },
+            None => {},
+        }
+
+        //tables are loaded once, even if referenced many times or recursively
+        while let Some(name) = pending.pop() {
+            if generator.tables.contains_key(&name) {
+                continue;
+            }
+            let table = parse_choices(config.get(&name).ok_or(format!("Table {} not found", name))?, &name)?;
+            pending.extend(references(&table));
+            generator.tables.insert(name, table);
+        }
+
+        Ok(Some(generator))
+    }
+
+    /// checks if a command is configured
+    pub fn has_command(&self, command: &str) -> bool {
+        self.commands.contains_key(command)
+    }
+
+    /// generates the text of a command, args and user fill {args} and {user}
+    /// recent choices are remembered and avoided in the given scope, usually the chat one
+    pub fn generate(&self, command: &str, args: &str, user: &str, scope: Option<&Scope>) -> Result<String, String> {
+        let templates = self.commands.get(command).ok_or(format!("Command {} not configured", command))?;
+        let mut rng = match self.seed {
+            Some(seed) => StdRng::from_seed(&[seed][..]),
+            None => StdRng::new().map_err(|e| format!("Unable to initialize random generator: {:?}", e))?,
+        };
+        let mut text = String::new();
+        let template = self.choose(&mut rng, &format!("/{}", command), templates, scope)?;
+        self.expand(&mut rng, template, args, user, scope, 0, &mut text)?;
+        Ok(text)
+    }
+
+    /// appends a choice to text, expanding its references
+    fn expand<R: Rng>(&self, rng: &mut R, choice: &Choice, args: &str, user: &str, scope: Option<&Scope>, depth: usize, text: &mut String) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err(String::from("Templates nested too deep"));
+        }
+        for part in &choice.parts {
+            match part {
+                &Part::Text(ref t) => text.push_str(t),
+                &Part::Args => if args.is_empty() {
+                    return Err(String::from("Missing arguments"));
+                }
+                else {
+                    text.push_str(args);
+                },
+                &Part::User => text.push_str(user),
+                &Part::Table(ref name) => {
+                    let table = self.tables.get(name).ok_or(format!("Table {} not found", name))?;
+                    let next = self.choose(rng, name, table, scope)?;
+                    self.expand(rng, next, args, user, scope, depth + 1, text)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// picks an entry by weight, skipping the ones recently picked in scope
+    fn choose<'a, R: Rng>(&self, rng: &mut R, name: &str, choices: &'a [Choice], scope: Option<&Scope>) -> Result<&'a Choice, String> {
+        //at least one entry must stay available
+        let keep = self.avoid_repeats.min(choices.len() - 1);
+        let key = format!("{}{}", RECENT_PREFIX, name);
+        let mut recent: Vec<usize> = match scope {
+            Some(s) if keep > 0 => s.get(&key)?.unwrap_or(Vec::new()),
+            _ => Vec::new(),
+        };
+        while recent.len() > keep {
+            recent.remove(0);
+        }
+
+        let candidates: Vec<usize> = (0..choices.len()).filter(|i| !recent.contains(i)).collect();
+        let total: u64 = candidates.iter().map(|&i| choices[i].weight).sum();
+        let mut pick = rng.gen_range(0, total);
+        let mut index = candidates[candidates.len() - 1];
+        for &i in &candidates {
+            if pick < choices[i].weight {
+                index = i;
+                break;
+            }
+            pick -= choices[i].weight;
+        }
+
+        match scope {
+            Some(s) if keep > 0 => {
+                recent.push(index);
+                if recent.len() > keep {
+                    recent.remove(0);
+                }
+                s.set(&key, &recent)?;
+            },
+            _ => {},
+        }
+        Ok(&choices[index])
+    }
+}
+
+/// reads a table: a single string, a list of entries or a table of numbered entries, sorted by number
+/// every entry is a template string, or a table with TEXT and WEIGHT
+fn parse_choices(value: &TomlValue, name: &str) -> Result<Vec<Choice>, String> {
+    let choices = match value {
+        &TomlValue::String(_) => vec![parse_choice(value, name)?],
+        &TomlValue::Array(ref entries) => entries.iter()
+            .enumerate()
+            .map(|(i, entry)| parse_choice(entry, &format!("{}[{}]", name, i)))
+            .collect::<Result<Vec<Choice>, String>>()?,
+        &TomlValue::Table(ref table) => {
+            let mut entries = table.iter()
+                .map(|(key, entry)| match key.parse::<u64>() {
+                    Ok(n) => parse_choice(entry, &format!("{}.{}", name, key)).map(|choice| (n, choice)),
+                    Err(_) => Err(format!("Error interpreting {}.{} config value", name, key)),
+                })
+                .collect::<Result<Vec<(u64, Choice)>, String>>()?;
+            entries.sort_by_key(|&(n, _)| n);
+            entries.into_iter().map(|(_, choice)| choice).collect()
+        },
+        _ => { return Err(format!("Error interpreting {} config value", name)); },
+    };
+    if choices.is_empty() {
+        return Err(format!("No entries in {}", name));
+    }
+    Ok(choices)
+}
+
+fn parse_choice(value: &TomlValue, name: &str) -> Result<Choice, String> {
+    let (template, weight) = match value {
+        &TomlValue::String(ref s) => (s.as_str(), 1),
+        &TomlValue::Table(ref entry) => (
+            entry.get("TEXT").and_then(|t| t.as_str()).ok_or(format!("Missing {}.TEXT config value", name))?,
+            match entry.get("WEIGHT") {
+                Some(w) => w.as_integer().and_then(|w| if w > 0 { Some(w as u64) } else { None })
+                    .ok_or(format!("Error interpreting {}.WEIGHT config value", name))?,
+                None => 1,
+            },
+        ),
+        _ => { return Err(format!("Error interpreting {} config value", name)); },
+    };
+    Ok(Choice {
+        parts: parse_template(template).map_err(|e| format!("{}: {}", name, e))?,
+        weight: weight,
+    })
+}
+
+/// splits a template into text and {references}, {{ and }} are literal braces
+fn parse_template(template: &str) -> Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            },
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            },
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(n) => name.push(n),
+                        None => { return Err(format!("Unclosed reference in template {}", template)); },
+                    }
+                }
+                if !text.is_empty() {
+                    parts.push(Part::Text(mem::replace(&mut text, String::new())));
+                }
+                parts.push(match name.as_str() {
+                    "args" => Part::Args,
+                    "user" => Part::User,
+                    "" => { return Err(format!("Empty reference in template {}", template)); },
+                    _ => Part::Table(name.clone()),
+                });
+            },
+            '}' => { return Err(format!("Unmatched }} in template {}", template)); },
+            _ => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+    Ok(parts)
+}
+
+/// returns the tables referenced by choices
+fn references(choices: &[Choice]) -> Vec<String> {
+    choices.iter()
+        .flat_map(|choice| choice.parts.iter())
+        .filter_map(|part| match part {
+            &Part::Table(ref name) => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// generates a command text and sends it where the command came from
+fn reply(api: &Telegram, config: &Arc<RwLock<TomlValue>>, session: &Session, command: &str, args: &Args, request: &Request) -> Result<JsonValue, String> {
+    let msg = match request.get_message() {
+        &Some(ref msg) => msg,
+        &None => { return Err(String::from("Empty message")); },
+    };
+    //config is read on every command, so that edits apply without reloading the bot
+    let generator = {
+        let config = config.read().map_err(|e| format!("Error read locking config: {:?}", e))?;
+        Generator::from_config(&config)?.ok_or(format!("{} section not found", GENERATOR_SECTION))?
+    };
+    let chat_id = msg.get_chat().get_id();
+    let text = generator.generate(command, &args.rest(0), msg.get_from().get_first_name(), Some(&session.chat(chat_id)))?;
+    api.send_message(&chat_id.to_string(), &text, None, None, None, None)
+}
+
+impl<B: Bot + 'static> Router<B> {
+    /// registers every command configured in the GENERATOR section, with its USAGE and DESCRIPTION,
+    /// replying with generated text, see Generator, through the Telegram instance returned by Bot::get_api
+    pub fn generators(self, config: &Arc<RwLock<TomlValue>>, session: &Arc<RwLock<HashMap<String, JsonValue>>>) -> Router<B> {
+        //config errors are reported when commands run
+        let commands: Vec<(String, String, String)> = match config.read() {
+            Ok(c) => match c.get(GENERATOR_SECTION).and_then(|s| s.get("COMMANDS")).and_then(|commands| commands.as_table()) {
+                Some(table) => table.iter()
+                    .map(|(name, command)| (
+                        name.clone(),
+                        command.get("USAGE").and_then(|u| u.as_str()).unwrap_or("").to_owned(),
+                        command.get("DESCRIPTION").and_then(|d| d.as_str()).unwrap_or("").to_owned(),
+                    ))
+                    .collect(),
+                None => Vec::new(),
+            },
+            Err(_) => Vec::new(),
+        };
+
+        commands.into_iter().fold(self, |router, (name, usage, description)| {
+            let config = config.clone();
+            let session = Session::new(session);
+            let command = name.clone();
+            router.command(&name, &usage, &description, move |bot: &B, args, request| {
+                let api = bot.get_api().ok_or(String::from("Bot::get_api is required to send generated text"))?;
+                reply(api, &config, &session, &command, args, request)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Generator;
+    use entities::Request;
+    use mock::{MockServer, json_ok};
+    use router::Router;
+    use serde_json;
+    use serde_json::value::Value as JsonValue;
+    use session::Session;
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+    use toml;
+    use {Bot, Telegram};
+
+    struct TestBot {
+        api: Telegram,
+    }
+
+    impl Bot for TestBot {
+        fn new(api: Telegram, _config: &Arc<RwLock<toml::Value>>, _session: &Arc<RwLock<HashMap<String, JsonValue>>>) -> TestBot {
+            TestBot {
+                api: api,
+            }
+        }
+
+        fn get_api(&self) -> Option<&Telegram> {
+            Some(&self.api)
+        }
+
+        fn dispatch(&self, _method: &str, _args: Vec<String>, _request: &Request) -> Result<JsonValue, String> {
+            Err(String::from("Not managed"))
+        }
+    }
+
+    const CONFIG: &'static str = r#"
+[GENERATOR]
+AVOID_REPEATS = 2
+SEED = 42
+
+[GENERATOR.COMMANDS.swear]
+DESCRIPTION = "swears"
+TEMPLATE = "{Exclamation}{Deity}{Suffix}"
+
+[GENERATOR.COMMANDS.swearto]
+USAGE = "<name>"
+DESCRIPTION = "swears to someone"
+TEMPLATE = "{args}, {user} says {Exclamation}{Deity}{Suffix}"
+
+[GENERATOR.COMMANDS.braces]
+TEMPLATE = ["{{{Deity}}}"]
+
+[Exclamation]
+0 = "Holy "
+1 = "Jumping "
+2 = "Great "
+
+[Deity]
+0 = "Christ"
+1 = { TEXT = "Zeus", WEIGHT = 5 }
+
+[Suffix]
+0 = ""
+1 = " on toast"
+"#;
+
+    #[test]
+    fn it_works() {
+        let config: toml::Value = toml::from_str(CONFIG).unwrap();
+        let generator = Generator::from_config(&config).unwrap().unwrap();
+        assert!(generator.has_command("swearto"));
+        assert!(!generator.has_command("Deity"));
+        assert_eq!(generator.generate("swear", "", "Test", None), generator.generate("swear", "", "Test", None));
+        assert_eq!(generator.generate("braces", "", "Test", None).map(|text| text.starts_with('{') && text.ends_with('}')), Ok(true));
+        assert_eq!(generator.generate("swearto", "", "Test", None), Err(String::from("Missing arguments")));
+        assert!(generator.generate("missing", "", "Test", None).is_err());
+
+        //recent choices aren't repeated in the same chat, even with a fixed seed
+        let raw_session = Arc::new(RwLock::new(HashMap::new()));
+        let session = Session::new(&raw_session);
+        let texts: Vec<String> = (0..3).map(|_| generator.generate("swear", "", "Test", Some(&session.chat(1))).unwrap()).collect();
+        let exclamations: Vec<&str> = texts.iter().map(|text| text.split(' ').next().unwrap()).collect();
+        assert!(exclamations.contains(&"Holy") && exclamations.contains(&"Jumping") && exclamations.contains(&"Great"));
+        assert_eq!(session.chat(1).get::<Vec<usize>>("generator:Exclamation").unwrap().map(|recent| recent.len()), Some(2));
+        assert!(session.chat(2).get::<Vec<usize>>("generator:Exclamation").unwrap().is_none());
+
+        assert!(Generator::from_config(&toml::from_str("[GENERATOR.COMMANDS.swear]\nTEMPLATE = \"{Missing}\"").unwrap()).is_err());
+        assert!(Generator::from_config(&toml::from_str("[GENERATOR.COMMANDS.swear]\nTEMPLATE = \"{Unclosed\"").unwrap()).is_err());
+        assert!(Generator::from_config(&toml::from_str("Table = []\n[GENERATOR.COMMANDS.swear]\nTEMPLATE = \"{Table}\"").unwrap()).is_err());
+        assert!(Generator::from_config(&toml::from_str("Table = [{ TEXT = \"a\", WEIGHT = 0 }]\n[GENERATOR.COMMANDS.swear]\nTEMPLATE = \"{Table}\"").unwrap()).is_err());
+        //recursive grammars load, but can't expand forever
+        let recursive = Generator::from_config(&toml::from_str("Loop = \"a{Loop}\"\n[GENERATOR.COMMANDS.loop]\nTEMPLATE = \"{Loop}\"").unwrap()).unwrap().unwrap();
+        assert_eq!(recursive.generate("loop", "", "Test", None), Err(String::from("Templates nested too deep")));
+        assert!(Generator::from_config(&toml::from_str("").unwrap()).unwrap().is_none());
+
+        //commands are registered in the router
+        let server = MockServer::start().expect("Unable to start mock server");
+        let config = Arc::new(RwLock::new(toml::from_str::<toml::Value>(CONFIG).unwrap()));
+        let mut api = Telegram::new("test");
+        api.api_url = server.get_url().to_owned();
+        let bot = TestBot::new(api, &config, &raw_session);
+        let router = Router::new().generators(&config, &raw_session);
+        assert_eq!(router.help_text(), "/braces\n/swear - swears\n/swearto <name> - swears to someone");
+
+        let request: Request = serde_json::from_str(r#"{"update_id":1,"message":{"message_id":1,"date":0,"chat":{"id":3,"type":"private"},"from":{"is_bot":false,"id":3,"first_name":"Test"},"text":"/swearto pippo"}}"#).unwrap();
+        assert_eq!(router.dispatch(&bot, "swearto", vec![String::from("pippo")], &request), Ok(json_ok()));
+        let calls = server.get_calls_to("sendMessage");
+        assert_eq!(calls.len(), 1);
+        assert!(calls[0].get_text("text").unwrap().starts_with("pippo, Test says "));
+        assert_eq!(router.dispatch(&bot, "swearto", Vec::new(), &request), Err(String::from("Missing arguments")));
+    }
+}
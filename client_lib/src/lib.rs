@@ -12,14 +12,17 @@
 extern crate serde_derive;
 extern crate serde;
 extern crate serde_json;
+extern crate rand;
 extern crate reqwest;
 extern crate toml;
 
 use std::collections::HashMap;
+use std::io::Cursor;
 use std::sync::Arc;
 use std::sync::RwLock;
 
-use reqwest::multipart::Form;
+use reqwest::multipart::{Form, Part};
+use reqwest::mime::Mime;
 use reqwest::Client;
 
 use serde_json::value::Value as JsonValue;
@@ -28,6 +31,27 @@ use toml::Value as TomlValue;
 
 /// Telegram bot entities
 pub mod entities;
+/// Telegram file_id cache
+pub mod cache;
+/// Typed, scoped view over bot session
+pub mod session;
+/// Multi-step conversations
+pub mod dialogue;
+/// Commands routing and arguments parsing
+pub mod router;
+/// Callback queries routing and callback_data encoding
+pub mod callback;
+/// Middlewares running around Bot::parse
+pub mod middleware;
+/// Users and chats access control
+pub mod acl;
+/// Live locations kept updated by the bot
+pub mod live_location;
+/// Text generated from word tables in bot config
+pub mod generator;
+/// In-process Telegram bot APIs imitation, for testing purposes
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 
 /// This enum describes all possible call params
 pub enum Param<'a> {
@@ -45,11 +69,47 @@ pub enum Param<'a> {
     ChatAction(entities::ChatAction),
 }
 
+/// default Telegram bot APIs base URL
+pub const DEFAULT_API_URL: &'static str = "https://api.telegram.org";
+
+/// field carrying the method name in webhook replies
+pub const WEBHOOK_METHOD: &'static str = "method";
+
+/// method returned by Bot::parse_* for updates the bot deliberately ignores, Bot::route answers them with null
+/// it can't clash with commands, which are made of letters, digits and underscores
+pub const IGNORE_METHOD: &'static str = "#ignore";
+
+/// compares two secrets in constant time, to avoid leaking how many leading bytes match
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// FNV-1a 64 bit offset basis, the initial value of fnv1a hashes
+pub const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// FNV-1a 64 bit hash of bytes, continuing the given one, start from FNV_OFFSET.
+/// Unlike std's DefaultHasher its values don't change across Rust releases, so they can be persisted
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// checks if a Bot::dispatch result is a webhook reply (see Telegram::via_webhook)
+pub fn is_webhook_reply(value: &JsonValue) -> bool {
+    value.get(WEBHOOK_METHOD).map(|method| method.is_string()).unwrap_or(false)
+}
+
 /// #Telegram
 /// This class defines all possible calls to Telegram bot APIs
 pub struct Telegram {
+    api_url: String,
     http_token: String,
     client: Client,
+    file_cache: Option<cache::FileCache>,
+    acl: Option<acl::Acl>,
+    webhook: bool,
 }
 
 impl Telegram {
@@ -63,7 +123,7 @@ impl Telegram {
                 cnf.get("SECRET").ok_or(String::from("SECRET config value not found"))
                     .and_then(|secret_value| secret_value.as_str().ok_or(String::from("Error interpreting SECRET config value")))
                     .and_then(|cnf_secret| {
-                        if secret != cnf_secret {
+                        if !constant_time_eq(secret.as_bytes(), cnf_secret.as_bytes()) {
                             Err(String::from("Secret mismatch"))
                         }
                         else {
@@ -71,18 +131,60 @@ impl Telegram {
                         }
                     })
                     .and_then(|token_value| token_value.as_str().ok_or(String::from("Error interpreting HTTP_TOKEN config value")))
-                    .and_then(|cnf_token| Ok(constructor(Telegram::new(cnf_token), config, session)))
+                    .and_then(|cnf_token| {
+                        let mut api = Telegram::new(cnf_token);
+                        match cnf.get("API_URL") {
+                            Some(url_value) => {
+                                let cnf_url = url_value.as_str().ok_or(String::from("Error interpreting API_URL config value"))?;
+                                api.api_url = cnf_url.trim_end_matches('/').to_owned();
+                            },
+                            None => {},
+                        }
+                        match cnf.get("FILE_CACHE") {
+                            Some(cache_value) => {
+                                let cnf_cache = cache_value.as_str().ok_or(String::from("Error interpreting FILE_CACHE config value"))?;
+                                api.file_cache = Some(cache::FileCache::from_config(cnf_cache, session));
+                            },
+                            None => {},
+                        }
+                        api.acl = acl::Acl::from_config(&cnf)?;
+                        Ok(constructor(api, config, session))
+                    })
             })
     }
 
     /// internal constructor
     fn new(token: &str) -> Telegram {
         Telegram {
+            api_url: String::from(DEFAULT_API_URL),
             http_token: token.to_owned(),
             client: Client::new(),
+            file_cache: None,
+            acl: None,
+            webhook: false,
         }
     }
 
+    /// returns an instance whose method calls aren't performed, but returned as webhook replies.
+    /// Returning a webhook reply from Bot::dispatch makes the server write it into the HTTP response,
+    /// saving a round trip. Only one call can be replied this way, its result isn't available
+    /// and files can't be uploaded, only sent by file_id or URL.
+    pub fn via_webhook(&self) -> Telegram {
+        Telegram {
+            api_url: self.api_url.clone(),
+            http_token: self.http_token.clone(),
+            client: self.client.clone(),
+            file_cache: None,
+            acl: None,
+            webhook: true,
+        }
+    }
+
+    /// returns the access control list configured in bot's toml, see acl::Acl
+    pub fn get_acl(&self) -> Option<&acl::Acl> {
+        self.acl.as_ref()
+    }
+
     /// #sendMessage
     /// Use this method to send text messages. On success, the sent Message is returned.
     pub fn send_message(&self, chat_id: &str, message: &str, reply_id: Option<&str>, preview: Option<bool>, parse_mode: Option<entities::ParseMode>, reply_markup: Option<entities::ReplyMarkup>) -> Result<JsonValue, String> {
@@ -141,7 +243,7 @@ impl Telegram {
     /// Use this method to get basic info about a file and prepare it for downloading.
     /// For the moment, bots can download files of up to 20MB in size.
     /// On success, a File object is returned.
-    /// The file can then be downloaded via the link https://api.telegram.org/file/bot<token>/<file_path>,
+    /// The file can then be downloaded via the link <API_URL>/file/bot<token>/<file_path>,
     /// where <file_path> is taken from the response.
     /// It is guaranteed that the link will be valid for at least 1 hour.
     /// When the link expires, a new one can be requested by calling getFile again.
@@ -152,7 +254,7 @@ impl Telegram {
         let res = self.call_telegram("getFile", params)?;
 
         res["result"]["file_path"].as_str().ok_or(String::from("Unable to retrieve file_path"))
-            .and_then(|file_path| self.client.get(&format!("https://api.telegram.org/bot{}/{}", self.http_token, file_path)).send()
+            .and_then(|file_path| self.client.get(&format!("{}/file/bot{}/{}", self.api_url, self.http_token, file_path)).send()
                 .and_then(|mut file| file.text())
                 .map_err(|e| format!("{:?}", e))
             )
@@ -203,10 +305,24 @@ impl Telegram {
     /// Bots can currently send audio files of up to 50 MB in size, this limit may be changed in the future.
     /// For sending voice messages, use the sendVoice method instead.
     pub fn send_audio(&self, chat_id: &str, audio: entities::InputFile, duration: Option<&str>, performer: Option<&str>, title: Option<&str>, reply_id: Option<&str>, reply_markup: Option<entities::ReplyMarkup>) -> Result<JsonValue, String> {
+        self.send_audio_with_thumb(chat_id, audio, duration, performer, title, None, reply_id, reply_markup)
+    }
+
+    /// #sendAudio
+    /// Same as send_audio, with a thumbnail.
+    /// The thumbnail should be in JPEG format and less than 200 kB in size, and must be uploaded as a new file.
+    pub fn send_audio_with_thumb(&self, chat_id: &str, audio: entities::InputFile, duration: Option<&str>, performer: Option<&str>, title: Option<&str>, thumb: Option<entities::InputFile>, reply_id: Option<&str>, reply_markup: Option<entities::ReplyMarkup>) -> Result<JsonValue, String> {
         let mut params = HashMap::new();
         params.insert("chat_id", Param::Value(chat_id));
         params.insert("audio", Param::File(audio));
 
+        match thumb {
+            Some(value) => {
+                params.insert("thumb", Param::File(value));
+            },
+            None => {},
+        }
+
         match duration {
             Some(value) => {
                 params.insert("duration", Param::Value(value));
@@ -284,10 +400,24 @@ impl Telegram {
     /// On success, the sent Message is returned.
     /// Bots can currently send files of any type of up to 50 MB in size, this limit may be changed in the future.
     pub fn send_document(&self, chat_id: &str, document: entities::InputFile, reply_id: Option<&str>, reply_markup: Option<entities::ReplyMarkup>) -> Result<JsonValue, String> {
+        self.send_document_with_thumb(chat_id, document, None, reply_id, reply_markup)
+    }
+
+    /// #sendDocument
+    /// Same as send_document, with a thumbnail.
+    /// The thumbnail should be in JPEG format and less than 200 kB in size, and must be uploaded as a new file.
+    pub fn send_document_with_thumb(&self, chat_id: &str, document: entities::InputFile, thumb: Option<entities::InputFile>, reply_id: Option<&str>, reply_markup: Option<entities::ReplyMarkup>) -> Result<JsonValue, String> {
         let mut params = HashMap::new();
         params.insert("chat_id", Param::Value(chat_id));
         params.insert("document", Param::File(document));
 
+        match thumb {
+            Some(value) => {
+                params.insert("thumb", Param::File(value));
+            },
+            None => {},
+        }
+
         match reply_id {
             Some(value) => {
                 params.insert("reply_to_message_id", Param::Value(value));
@@ -317,14 +447,242 @@ impl Telegram {
         self.call_telegram("sendChatAction", params)
     }
 
+    /// #sendLocation
+    /// Use this method to send point on the map. On success, the sent Message is returned.
+    /// live_period, between 60 and 86400 seconds, sends a live location that can be edited with editMessageLiveLocation.
+    pub fn send_location(&self, chat_id: &str, latitude: f64, longitude: f64, live_period: Option<u32>, reply_id: Option<&str>, reply_markup: Option<entities::ReplyMarkup>) -> Result<JsonValue, String> {
+        let latitude = latitude.to_string();
+        let longitude = longitude.to_string();
+        let live_period = live_period.map(|period| period.to_string());
+        let mut params = HashMap::new();
+        params.insert("chat_id", Param::Value(chat_id));
+        params.insert("latitude", Param::Value(&latitude));
+        params.insert("longitude", Param::Value(&longitude));
+
+        match live_period {
+            Some(ref value) => {
+                params.insert("live_period", Param::Value(value));
+            },
+            None => {},
+        }
+
+        match reply_id {
+            Some(value) => {
+                params.insert("reply_to_message_id", Param::Value(value));
+            },
+            None => {},
+        }
+
+        match reply_markup {
+            Some(value) => {
+                params.insert("reply_markup", Param::ReplyMarkup(value));
+            },
+            None => {},
+        }
+
+        self.call_telegram("sendLocation", params)
+    }
+
+    /// #editMessageLiveLocation
+    /// Use this method to edit live location messages sent by the bot.
+    /// A location can be edited until its live_period expires or editing is explicitly disabled by a call to stopMessageLiveLocation.
+    /// On success, the edited Message is returned.
+    pub fn edit_message_live_location(&self, chat_id: &str, message_id: &str, latitude: f64, longitude: f64, reply_markup: Option<entities::ReplyMarkup>) -> Result<JsonValue, String> {
+        let latitude = latitude.to_string();
+        let longitude = longitude.to_string();
+        let mut params = HashMap::new();
+        params.insert("chat_id", Param::Value(chat_id));
+        params.insert("message_id", Param::Value(message_id));
+        params.insert("latitude", Param::Value(&latitude));
+        params.insert("longitude", Param::Value(&longitude));
+
+        match reply_markup {
+            Some(value) => {
+                params.insert("reply_markup", Param::ReplyMarkup(value));
+            },
+            None => {},
+        }
+
+        self.call_telegram("editMessageLiveLocation", params)
+    }
+
+    /// #stopMessageLiveLocation
+    /// Use this method to stop updating a live location message sent by the bot before live_period expires.
+    /// On success, the stopped Message is returned.
+    pub fn stop_message_live_location(&self, chat_id: &str, message_id: &str, reply_markup: Option<entities::ReplyMarkup>) -> Result<JsonValue, String> {
+        let mut params = HashMap::new();
+        params.insert("chat_id", Param::Value(chat_id));
+        params.insert("message_id", Param::Value(message_id));
+
+        match reply_markup {
+            Some(value) => {
+                params.insert("reply_markup", Param::ReplyMarkup(value));
+            },
+            None => {},
+        }
+
+        self.call_telegram("stopMessageLiveLocation", params)
+    }
+
+    /// #editMessageText
+    /// Use this method to edit text messages sent by the bot.
+    /// On success, the edited Message is returned.
+    pub fn edit_message_text(&self, chat_id: &str, message_id: &str, text: &str, parse_mode: Option<entities::ParseMode>, reply_markup: Option<entities::ReplyMarkup>) -> Result<JsonValue, String> {
+        let mut params = HashMap::new();
+        params.insert("chat_id", Param::Value(chat_id));
+        params.insert("message_id", Param::Value(message_id));
+        params.insert("text", Param::Value(text));
+
+        match parse_mode {
+            Some(value) => {
+                params.insert("parse_mode", Param::ParseMode(value));
+            },
+            None => {},
+        }
+
+        match reply_markup {
+            Some(value) => {
+                params.insert("reply_markup", Param::ReplyMarkup(value));
+            },
+            None => {},
+        }
+
+        self.call_telegram("editMessageText", params)
+    }
+
+    /// #answerCallbackQuery
+    /// Use this method to send answers to callback queries sent from inline keyboards.
+    /// The answer will be displayed to the user as a notification at the top of the chat screen or as an alert.
+    /// On success, True is returned.
+    pub fn answer_callback_query(&self, callback_query_id: &str, text: Option<&str>, show_alert: Option<bool>, url: Option<&str>, cache_time: Option<&str>) -> Result<JsonValue, String> {
+        let mut params = HashMap::new();
+        params.insert("callback_query_id", Param::Value(callback_query_id));
+
+        match text {
+            Some(value) => {
+                params.insert("text", Param::Value(value));
+            },
+            None => {},
+        }
+
+        match show_alert {
+            Some(value) => {
+                params.insert("show_alert", Param::Flag(value));
+            },
+            None => {},
+        }
+
+        match url {
+            Some(value) => {
+                params.insert("url", Param::Value(value));
+            },
+            None => {},
+        }
+
+        match cache_time {
+            Some(value) => {
+                params.insert("cache_time", Param::Value(value));
+            },
+            None => {},
+        }
+
+        self.call_telegram("answerCallbackQuery", params)
+    }
+
+    /// #getChatMember
+    /// Use this method to get information about a member of a chat.
+    /// Returns a ChatMember object on success.
+    pub fn get_chat_member(&self, chat_id: &str, user_id: &str) -> Result<JsonValue, String> {
+        let mut params = HashMap::new();
+        params.insert("chat_id", Param::Value(chat_id));
+        params.insert("user_id", Param::Value(user_id));
+
+        self.call_telegram("getChatMember", params)
+    }
+
     /// internal call facility
     fn call_telegram(&self, method: &str, params: HashMap<&str, Param>) -> Result<JsonValue, String> {
-        let url = format!("https://api.telegram.org/bot{}/{}", self.http_token, method);
-        self.client.post(&url)
+        if self.webhook {
+            return Telegram::write_webhook_reply(method, params);
+        }
+
+        let (params, hits, misses) = self.apply_file_cache(params);
+
+        let url = format!("{}/bot{}/{}", self.api_url, self.http_token, method);
+        let res: JsonValue = self.client.post(&url)
             .multipart(Telegram::write_body(params)?)
             .send()
             .and_then(|mut response| response.json())
-            .map_err(|e| format!("{:?}", e))
+            .map_err(|e| format!("{:?}", e))?;
+
+        self.store_file_cache(&res, hits, misses);
+
+        Ok(res)
+    }
+
+    /// internal file cache lookup, replaces already uploaded local files with their file_id
+    fn apply_file_cache<'a>(&self, params: HashMap<&'a str, Param<'a>>) -> (HashMap<&'a str, Param<'a>>, Vec<String>, Vec<(&'a str, String)>) {
+        let mut hits = Vec::new();
+        let mut misses = Vec::new();
+
+        let cache = match self.file_cache {
+            Some(ref cache) => cache,
+            None => { return (params, hits, misses); },
+        };
+
+        let params = params.into_iter()
+            .map(|(name, value)| match value {
+                //thumbnails can't be sent by file_id
+                Param::File(entities::InputFile::File(path)) => if name == "thumb" {
+                    (name, Param::File(entities::InputFile::File(path)))
+                }
+                else {
+                    match cache::FileCache::key(&path) {
+                        Ok(key) => match cache.get(&key) {
+                            Some(file_id) => {
+                                hits.push(key);
+                                (name, Param::File(entities::InputFile::FileId(file_id)))
+                            },
+                            None => {
+                                misses.push((name, key));
+                                (name, Param::File(entities::InputFile::File(path)))
+                            },
+                        },
+                        Err(_) => (name, Param::File(entities::InputFile::File(path))),
+                    }
+                },
+                other => (name, other),
+            })
+            .collect();
+
+        (params, hits, misses)
+    }
+
+    /// internal file cache update, stores file_ids of newly uploaded files and drops refused ones
+    fn store_file_cache(&self, res: &JsonValue, hits: Vec<String>, misses: Vec<(&str, String)>) {
+        let cache = match self.file_cache {
+            Some(ref cache) => cache,
+            None => { return; },
+        };
+
+        //cache errors don't make calls fail
+        if res["ok"].as_bool() == Some(true) {
+            for (name, key) in misses {
+                match cache::FileCache::extract_file_id(name, &res["result"]) {
+                    Some(file_id) => if let Err(e) = cache.set(&key, file_id) {
+                        println!("Unable to cache file_id for {}: {}", key, e);
+                    },
+                    None => {},
+                }
+            }
+        }
+        else if cache::FileCache::is_file_error(res) {
+            for key in hits {
+                if let Err(e) = cache.remove(&key) {
+                    println!("Unable to remove file_id for {}: {}", key, e);
+                }
+            }
+        }
     }
 
     /// internal multipart writer
@@ -334,13 +692,7 @@ impl Telegram {
         for (name, value) in params {
             form = match value {
                 Param::Value(s) => form.text::<String, String>(name.to_owned(), s.to_owned()),
-                Param::File(v) => match v {
-                    entities::InputFile::File(s) => match form.file::<String, String>(name.to_owned(), s.to_owned()) {
-                        Ok(f) => f,
-                        Err(e) => { return Err(format!("Unable to add file field {} to request: {:?}", name, e)); },
-                    },
-                    entities::InputFile::FileId(s) | entities::InputFile::Url(s) => form.text::<String, String>(name.to_owned(), s.to_owned()),
-                },
+                Param::File(v) => Telegram::write_file(form, name, v)?,
                 Param::Flag(v) => form.text::<String, String>(name.to_owned(), String::from(if v { "true" } else { "false" })),
                 Param::ParseMode(ref v) => form.text::<String, String>(name.to_owned(), v.to_string()),
                 Param::ReplyMarkup(ref v) => match serde_json::to_string(v) {
@@ -353,6 +705,56 @@ impl Telegram {
 
         Ok(form)
     }
+
+    /// internal webhook reply writer
+    fn write_webhook_reply(method: &str, params: HashMap<&str, Param>) -> Result<JsonValue, String> {
+        let mut reply = serde_json::Map::new();
+        reply.insert(String::from(WEBHOOK_METHOD), JsonValue::String(method.to_owned()));
+
+        for (name, value) in params {
+            let json = match value {
+                Param::Value(s) => JsonValue::String(s.to_owned()),
+                Param::File(entities::InputFile::FileId(s)) | Param::File(entities::InputFile::Url(s)) => JsonValue::String(s),
+                Param::File(_) => { return Err(format!("Unable to add file field {} to webhook reply: files can't be uploaded", name)); },
+                Param::Flag(v) => JsonValue::Bool(v),
+                Param::ParseMode(ref v) => JsonValue::String(v.to_string()),
+                Param::ReplyMarkup(ref v) => serde_json::to_value(v).map_err(|e| format!("Unable to add reply_markup field {} to webhook reply: {:?}", name, e))?,
+                Param::ChatAction(ref v) => JsonValue::String(v.to_string()),
+            };
+            reply.insert(name.to_owned(), json);
+        }
+
+        Ok(JsonValue::Object(reply))
+    }
+
+    /// internal multipart file writer
+    fn write_file(form: Form, name: &str, file: entities::InputFile) -> Result<Form, String> {
+        match file {
+            entities::InputFile::File(s) => form.file::<String, String>(name.to_owned(), s.to_owned())
+                .map_err(|e| format!("Unable to add file field {} to request: {:?}", name, e)),
+            entities::InputFile::FileId(s) | entities::InputFile::Url(s) => Ok(form.text::<String, String>(name.to_owned(), s.to_owned())),
+            entities::InputFile::Bytes { name: file_name, mime, data } => {
+                let length = data.len() as u64;
+                Telegram::write_part(Part::reader_with_length(Cursor::new(data), length), name, file_name, mime)
+                    .map(|part| form.part::<String>(name.to_owned(), part))
+            },
+            entities::InputFile::Reader { name: file_name, mime, reader } => {
+                Telegram::write_part(Part::reader(reader), name, file_name, mime)
+                    .map(|part| form.part::<String>(name.to_owned(), part))
+            },
+        }
+    }
+
+    /// internal multipart part decorator
+    fn write_part(part: Part, name: &str, file_name: String, mime: Option<String>) -> Result<Part, String> {
+        let part = part.file_name(file_name);
+        match mime {
+            Some(value) => value.parse::<Mime>()
+                .map(|m| part.mime(m))
+                .map_err(|e| format!("Unable to parse mime type {} for file field {}: {:?}", value, name, e)),
+            None => Ok(part),
+        }
+    }
 }
 
 /// base trait for Telegram bots
@@ -360,8 +762,40 @@ pub trait Bot {
     /// creates a new instance of the Bot
     fn new(api: Telegram, config: &Arc<RwLock<TomlValue>>, session: &Arc<RwLock<HashMap<String, JsonValue>>>) -> Self;
 
-    /// uses the correct method to retrieve method and arguments from Request, then dispatches it
+    /// runs the Request through the bot's middlewares, if any, then routes it
     fn parse(&self, request: &entities::Request) -> Result<JsonValue, String> {
+        match self.middlewares() {
+            Some(pipeline) => pipeline.run(self, request, |request| self.route(request)),
+            None => self.route(request),
+        }
+    }
+
+    /// returns the middlewares wrapping every Request, see middleware::Pipeline
+    fn middlewares(&self) -> Option<&middleware::Pipeline<Self>> {
+        None
+    }
+
+    /// returns the Telegram instance the bot was created with, used to enforce the configured acl::Acl
+    /// bots not returning it aren't restricted by the ACL
+    fn get_api(&self) -> Option<&Telegram> {
+        None
+    }
+
+    /// uses the correct method to retrieve method and arguments from Request, then dispatches it
+    /// requests denied by the ACL never reach dialogues nor dispatch
+    fn route(&self, request: &entities::Request) -> Result<JsonValue, String> {
+        match self.get_api() {
+            Some(api) => match acl::enforce(api, None, request)? {
+                Some(denied) => { return Ok(denied); },
+                None => {},
+            },
+            None => {},
+        }
+        //answers to an active dialogue skip the usual routing
+        match self.parse_dialogue(request) {
+            Some(res) => { return res; },
+            None => {},
+        }
         let (method, args) = match request.get_type()? {
             entities::RequestType::Message => self.parse_message(request)?,
             entities::RequestType::EditedMesage => self.parse_edited_message(request)?,
@@ -369,9 +803,25 @@ pub trait Bot {
             entities::RequestType::ChosenInlineResult => self.parse_chosen_inline_result(request)?,
             entities::RequestType::CallbackQuery => self.parse_callback_query(request)?,
         };
+        if method == IGNORE_METHOD {
+            return Ok(JsonValue::Null);
+        }
+        match self.get_api() {
+            Some(api) => match acl::enforce(api, Some(&method), request)? {
+                Some(denied) => { return Ok(denied); },
+                None => {},
+            },
+            None => {},
+        }
         self.dispatch(&method, args, request)
     }
 
+    /// routes a Request to the active dialogue, if any, usually calling dialogue::Dialogues::handle
+    /// returning None lets the Request follow the usual routing
+    fn parse_dialogue(&self, _request: &entities::Request) -> Option<Result<JsonValue, String>> {
+        None
+    }
+
     /// given a Request of type Message, retrieve method ad arguments
     fn parse_message(&self, _request: &entities::Request) -> Result<((String, Vec<String>)), String> {
         Err(String::from("Not managed"))
@@ -398,22 +848,117 @@ pub trait Bot {
     }
 
     /// given a method and it's arguments, perform the operation on the given Request
+    /// a webhook reply (see Telegram::via_webhook) can be returned to let the server perform the call
     fn dispatch(&self, method: &str, args: Vec<String>, request: &entities::Request) -> Result<JsonValue, String>;
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Telegram, serde_json};
+    use super::{Telegram, Bot, Param, serde_json, toml, is_webhook_reply};
+    use super::entities::{InputFile, ParseMode, Request};
     use super::serde_json::value::Value;
+    use super::mock::{MockServer, json_error, json_result};
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::sync::{Arc, RwLock};
 
     #[test]
     fn it_works() {
-        let client = Telegram::new("test");
+        let server = MockServer::start().expect("Unable to start mock server");
+        server.respond("sendMessage", json_error(404, "Not Found"));
+        let mut client = Telegram::new("test");
+        client.api_url = server.get_url().to_owned();
         let res = client.send_message("123", "prova", None, None, None, None);
 
         assert_eq!(
             serde_json::from_str::<Value>("{\"ok\":false,\"error_code\":404,\"description\":\"Not Found\"}").expect("Unable to json encode test string"),
             res.expect("Failed call")
         );
+        assert!(server.was_called("sendMessage", &[("chat_id", "123"), ("text", "prova")]));
+    }
+
+    #[test]
+    fn webhook_reply() {
+        let server = MockServer::start().expect("Unable to start mock server");
+        let mut client = Telegram::new("test");
+        client.api_url = server.get_url().to_owned();
+        let res = client.via_webhook().send_message("123", "prova", Some("42"), None, Some(ParseMode::Markdown), None).expect("Failed webhook reply");
+
+        assert!(is_webhook_reply(&res));
+        assert_eq!(res, serde_json::from_str::<Value>(r#"{"method":"sendMessage","chat_id":"123","text":"prova","reply_to_message_id":"42","disable_web_page_preview":true,"parse_mode":"Markdown"}"#).unwrap());
+        assert!(client.via_webhook().send_document("123", InputFile::File(String::from("Cargo.toml")), None, None).is_err());
+        assert!(server.get_calls().is_empty());
+        assert!(!is_webhook_reply(&client.send_message("123", "prova", None, None, None, None).expect("Failed call")));
+    }
+
+    #[test]
+    fn api_url_from_config() {
+        let config = Arc::new(RwLock::new(toml::from_str::<toml::Value>(r#"SECRET = "prova"
+HTTP_TOKEN = "test"
+API_URL = "http://127.0.0.1:8081/""#).unwrap()));
+        let session = Arc::new(RwLock::new(HashMap::new()));
+        let bot = Telegram::init_bot(TestBot::new, "prova", &config, &session).expect("Unable to init bot");
+
+        assert_eq!(bot.api.api_url, "http://127.0.0.1:8081");
+    }
+
+    struct TestBot {
+        api: Telegram,
+    }
+
+    impl Bot for TestBot {
+        fn new(api: Telegram, _config: &Arc<RwLock<toml::Value>>, _session: &Arc<RwLock<HashMap<String, Value>>>) -> TestBot {
+            TestBot {
+                api: api,
+            }
+        }
+
+        fn get_api(&self) -> Option<&Telegram> {
+            Some(&self.api)
+        }
+
+        fn dispatch(&self, _method: &str, _args: Vec<String>, _request: &Request) -> Result<Value, String> {
+            Err(String::from("Not managed"))
+        }
+    }
+
+    #[test]
+    fn file_cache() {
+        let server = MockServer::start().expect("Unable to start mock server");
+        let session = Arc::new(RwLock::new(HashMap::new()));
+        let mut client = Telegram::new("test");
+        client.api_url = server.get_url().to_owned();
+        client.file_cache = Some(super::cache::FileCache::from_config("session", &session));
+
+        server.respond("sendDocument", json_result(serde_json::from_str(r#"{"document":{"file_id":"doc"}}"#).unwrap()));
+        client.send_document("123", InputFile::File(String::from("Cargo.toml")), None, None).expect("Failed call");
+        client.send_document("123", InputFile::File(String::from("Cargo.toml")), None, None).expect("Failed call");
+        assert!(server.was_called("sendDocument", &[("document", "doc")]));
+
+        //unrelated errors keep the cached file_id
+        server.respond("sendDocument", json_error(400, "Bad Request: chat not found"));
+        client.send_document("0", InputFile::File(String::from("Cargo.toml")), None, None).expect("Failed call");
+        server.clear();
+        client.send_document("123", InputFile::File(String::from("Cargo.toml")), None, None).expect("Failed call");
+        assert!(server.was_called("sendDocument", &[("document", "doc")]));
+
+        //refused file_ids are dropped
+        server.respond("sendDocument", json_error(400, "Bad Request: wrong file identifier/HTTP URL specified"));
+        client.send_document("123", InputFile::File(String::from("Cargo.toml")), None, None).expect("Failed call");
+        server.clear();
+        client.send_document("123", InputFile::File(String::from("Cargo.toml")), None, None).expect("Failed call");
+        assert!(!server.was_called("sendDocument", &[("document", "doc")]));
+    }
+
+    #[test]
+    fn write_in_memory_files() {
+        let mut params = HashMap::new();
+        params.insert("document", Param::File(InputFile::Bytes { name: String::from("report.csv"), mime: Some(String::from("text/csv")), data: b"a,b\n1,2\n".to_vec() }));
+        params.insert("thumb", Param::File(InputFile::Reader { name: String::from("thumb.jpg"), mime: None, reader: Box::new(Cursor::new(vec![0xFFu8, 0xD8])) }));
+        assert!(Telegram::write_body(params).is_ok());
+
+        let mut params = HashMap::new();
+        params.insert("document", Param::File(InputFile::Bytes { name: String::from("report.csv"), mime: Some(String::from("not a mime")), data: Vec::new() }));
+        assert!(Telegram::write_body(params).is_err());
     }
 }
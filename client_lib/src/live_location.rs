@@ -0,0 +1,195 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::value::Value as JsonValue;
+
+use entities::ReplyMarkup;
+use Telegram;
+
+/// default minimum seconds between edits, Telegram allows about 20 messages per minute in groups
+pub const DEFAULT_INTERVAL: u64 = 3;
+
+/// #LiveLocation
+/// A live location sent by the bot, kept updated from a stream of coordinates.
+/// Points coming faster than the minimum interval are merged, only the latest one is sent once the interval elapses,
+/// and a 429 reply postpones the next edit by its retry_after.
+#[derive(Debug)]
+pub struct LiveLocation {
+    chat_id: String,
+    message_id: String,
+    interval: Duration,
+    expires: Instant,
+    last_edit: Instant,
+    retry_after: Option<Instant>,
+    last: (f64, f64),
+    pending: Option<(f64, f64)>,
+}
+
+impl LiveLocation {
+    /// sends a live location, that can be updated for live_period seconds
+    pub fn start(api: &Telegram, chat_id: &str, latitude: f64, longitude: f64, live_period: u32, reply_id: Option<&str>, reply_markup: Option<ReplyMarkup>) -> Result<LiveLocation, String> {
+        let res = api.send_location(chat_id, latitude, longitude, Some(live_period), reply_id, reply_markup)?;
+        let message_id = res["result"]["message_id"].as_u64().ok_or(format!("Unable to send live location: {}", res))?;
+        let now = Instant::now();
+        Ok(LiveLocation {
+            chat_id: chat_id.to_owned(),
+            message_id: message_id.to_string(),
+            interval: Duration::from_secs(DEFAULT_INTERVAL),
+            expires: now + Duration::from_secs(live_period as u64),
+            last_edit: now,
+            retry_after: None,
+            last: (latitude, longitude),
+            pending: None,
+        })
+    }
+
+    /// sets the minimum time between edits
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// returns chat_id
+    pub fn get_chat_id(&self) -> &str {
+        &self.chat_id
+    }
+
+    /// returns message_id
+    pub fn get_message_id(&self) -> &str {
+        &self.message_id
+    }
+
+    /// returns the last position sent, as latitude and longitude
+    pub fn get_position(&self) -> (f64, f64) {
+        self.last
+    }
+
+    /// checks if live_period is over, expired locations can't be edited anymore
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires
+    }
+
+    /// returns when the next edit is allowed
+    fn get_next_edit(&self) -> Instant {
+        let next = self.last_edit + self.interval;
+        match self.retry_after {
+            Some(retry) if retry > next => retry,
+            _ => next,
+        }
+    }
+
+    /// queues a new position, and sends it if the minimum interval elapsed
+    /// returns whether the message was edited
+    pub fn update(&mut self, api: &Telegram, latitude: f64, longitude: f64) -> Result<bool, String> {
+        self.pending = Some((latitude, longitude));
+        self.flush(api)
+    }
+
+    /// sends the queued position, if any and if the minimum interval elapsed
+    /// returns whether the message was edited
+    pub fn flush(&mut self, api: &Telegram) -> Result<bool, String> {
+        let (latitude, longitude) = match self.pending {
+            Some(p) => p,
+            None => { return Ok(false); },
+        };
+        //Telegram refuses edits that don't change the message
+        if (latitude, longitude) == self.last {
+            self.pending = None;
+            return Ok(false);
+        }
+        if self.is_expired() {
+            return Err(String::from("Live location expired"));
+        }
+        let now = Instant::now();
+        if now < self.get_next_edit() {
+            return Ok(false);
+        }
+
+        let res = api.edit_message_live_location(&self.chat_id, &self.message_id, latitude, longitude, None)?;
+        match (res["ok"].as_bool(), res["parameters"]["retry_after"].as_u64()) {
+            (Some(true), _) => {
+                self.last = (latitude, longitude);
+                self.last_edit = now;
+                self.pending = None;
+                Ok(true)
+            },
+            //flood control, the position stays queued
+            (_, Some(seconds)) => {
+                self.retry_after = Some(now + Duration::from_secs(seconds));
+                Ok(false)
+            },
+            _ => Err(format!("Unable to edit live location: {}", res)),
+        }
+    }
+
+    /// keeps the location updated from a stream of latitude and longitude pairs, blocking until the stream ends,
+    /// then waits for the latest queued position to be sent
+    pub fn follow<I>(&mut self, api: &Telegram, points: I) -> Result<(), String>
+        where I: IntoIterator<Item = (f64, f64)>
+    {
+        for (latitude, longitude) in points {
+            self.update(api, latitude, longitude)?;
+        }
+        while self.pending.is_some() {
+            let next = self.get_next_edit();
+            let now = Instant::now();
+            if next > now {
+                thread::sleep(next - now);
+            }
+            self.flush(api)?;
+        }
+        Ok(())
+    }
+
+    /// stops updating the live location before live_period expires
+    pub fn stop(self, api: &Telegram, reply_markup: Option<ReplyMarkup>) -> Result<JsonValue, String> {
+        api.stop_message_live_location(&self.chat_id, &self.message_id, reply_markup)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LiveLocation;
+    use mock::{MockServer, json_error, json_result};
+    use serde_json;
+    use std::time::Duration;
+    use Telegram;
+
+    #[test]
+    fn it_works() {
+        let server = MockServer::start().expect("Unable to start mock server");
+        server.respond("sendLocation", json_result(serde_json::from_str(r#"{"message_id":5,"date":0,"chat":{"id":1,"type":"private"},"location":{"latitude":45.0,"longitude":12.0}}"#).unwrap()));
+        let mut api = Telegram::new("test");
+        api.api_url = server.get_url().to_owned();
+
+        let mut location = LiveLocation::start(&api, "1", 45.0, 12.0, 60, None, None).expect("Unable to start live location")
+            .interval(Duration::from_millis(0));
+        assert_eq!(location.get_message_id(), "5");
+        assert!(!location.is_expired());
+        assert!(server.was_called("sendLocation", &[("chat_id", "1"), ("latitude", "45"), ("longitude", "12"), ("live_period", "60")]));
+
+        assert_eq!(location.update(&api, 45.1, 12.1), Ok(true));
+        assert!(server.was_called("editMessageLiveLocation", &[("chat_id", "1"), ("message_id", "5"), ("latitude", "45.1"), ("longitude", "12.1")]));
+        //unchanged positions aren't sent
+        assert_eq!(location.update(&api, 45.1, 12.1), Ok(false));
+        assert_eq!(server.get_calls_to("editMessageLiveLocation").len(), 1);
+
+        //flood control postpones edits, keeping only the latest position
+        let mut flood = json_error(429, "Too Many Requests: retry after 1");
+        flood["parameters"] = serde_json::from_str(r#"{"retry_after":1}"#).unwrap();
+        server.respond("editMessageLiveLocation", flood);
+        assert_eq!(location.update(&api, 45.2, 12.2), Ok(false));
+        assert_eq!(location.update(&api, 45.3, 12.3), Ok(false));
+        assert_eq!(server.get_calls_to("editMessageLiveLocation").len(), 2);
+        location.follow(&api, vec![(45.4, 12.4)]).expect("Unable to follow positions");
+        assert_eq!(server.get_calls_to("editMessageLiveLocation").len(), 3);
+        assert!(server.was_called("editMessageLiveLocation", &[("latitude", "45.4"), ("longitude", "12.4")]));
+        assert_eq!(location.get_position(), (45.4, 12.4));
+
+        server.respond("editMessageLiveLocation", json_error(400, "Bad Request: message can't be edited"));
+        assert!(location.update(&api, 45.5, 12.5).is_err());
+
+        assert!(location.stop(&api, None).is_ok());
+        assert!(server.was_called("stopMessageLiveLocation", &[("chat_id", "1"), ("message_id", "5")]));
+    }
+}
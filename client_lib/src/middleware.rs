@@ -0,0 +1,204 @@
+use serde_json::value::Value as JsonValue;
+
+use entities::Request;
+
+/// #Middleware
+/// Code running around Bot::parse, for cross-cutting concerns like logging, auth checks or anti-spam.
+/// Both methods have a default implementation, so that a middleware only implements what it needs.
+pub trait Middleware<B: ?Sized> {
+    /// inspects a Request before the bot parses it
+    /// returning a result short-circuits the pipeline: later middlewares and the bot aren't called
+    fn before(&self, _bot: &B, _request: &Request) -> Option<Result<JsonValue, String>> {
+        None
+    }
+
+    /// decorates the result, or handles the error, of the bot or of a later middleware
+    fn after(&self, _bot: &B, _request: &Request, result: Result<JsonValue, String>) -> Result<JsonValue, String> {
+        result
+    }
+}
+
+/// #Pipeline
+/// A chain of middlewares, plugged into Bot::middlewares.
+/// Middlewares run before the bot in the order they were added, and after it in reverse order,
+/// a short-circuited result only goes through the middlewares that already ran.
+pub struct Pipeline<B: ?Sized> {
+    middlewares: Vec<Box<dyn Middleware<B>>>,
+}
+
+impl<B: ?Sized> Pipeline<B> {
+    /// creates an empty pipeline
+    pub fn new() -> Pipeline<B> {
+        Pipeline {
+            middlewares: Vec::new(),
+        }
+    }
+
+    /// appends a middleware
+    pub fn add<M>(mut self, middleware: M) -> Pipeline<B>
+        where M: Middleware<B> + 'static
+    {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// runs the Request through every middleware, calling next in the middle
+    pub fn run<F>(&self, bot: &B, request: &Request, next: F) -> Result<JsonValue, String>
+        where F: FnOnce(&Request) -> Result<JsonValue, String>
+    {
+        let mut ran = 0;
+        let mut res = None;
+        for middleware in &self.middlewares {
+            res = middleware.before(bot, request);
+            if res.is_some() {
+                break;
+            }
+            ran += 1;
+        }
+        let mut res = match res {
+            Some(r) => r,
+            None => next(request),
+        };
+        for middleware in self.middlewares[..ran].iter().rev() {
+            res = middleware.after(bot, request, res);
+        }
+        res
+    }
+}
+
+/// #Logger
+/// Reports every Request type and outcome, as a line passed to the given function,
+/// so that bots choose where logs go
+pub struct Logger {
+    name: String,
+    log: Box<dyn Fn(&str)>,
+}
+
+impl Logger {
+    /// creates a logger, prefixing lines with the given name
+    pub fn new<F>(name: &str, log: F) -> Logger
+        where F: Fn(&str) + 'static
+    {
+        Logger {
+            name: name.to_owned(),
+            log: Box::new(log),
+        }
+    }
+}
+
+impl<B: ?Sized> Middleware<B> for Logger {
+    fn after(&self, _bot: &B, request: &Request, result: Result<JsonValue, String>) -> Result<JsonValue, String> {
+        let update_id = request.get_update_id().map(|id| id.to_string()).unwrap_or(String::from("-"));
+        match result {
+            Ok(_) => (self.log)(&format!("{}: update {} ({:?}) handled", self.name, update_id, request.get_type().ok())),
+            Err(ref e) => (self.log)(&format!("{}: update {} ({:?}) failed: {}", self.name, update_id, request.get_type().ok(), e)),
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Logger, Middleware, Pipeline};
+    use entities::Request;
+    use serde_json;
+    use serde_json::value::Value as JsonValue;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use std::sync::{Arc, RwLock};
+    use toml::Value as TomlValue;
+    use {Bot, Telegram};
+
+    struct TestBot {
+        api: Telegram,
+        pipeline: Pipeline<TestBot>,
+        calls: RefCell<Vec<String>>,
+        logs: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Bot for TestBot {
+        fn new(api: Telegram, _config: &Arc<RwLock<TomlValue>>, _session: &Arc<RwLock<HashMap<String, JsonValue>>>) -> TestBot {
+            let logs = Rc::new(RefCell::new(Vec::new()));
+            let lines = logs.clone();
+            TestBot {
+                api: api,
+                pipeline: Pipeline::new()
+                    .add(Logger::new("test", move |line: &str| lines.borrow_mut().push(line.to_owned())))
+                    .add(Trace("outer"))
+                    .add(Banned(13))
+                    .add(Trace("inner")),
+                calls: RefCell::new(Vec::new()),
+                logs: logs,
+            }
+        }
+
+        fn get_api(&self) -> Option<&Telegram> {
+            Some(&self.api)
+        }
+
+        fn middlewares(&self) -> Option<&Pipeline<TestBot>> {
+            Some(&self.pipeline)
+        }
+
+        fn parse_message(&self, request: &Request) -> Result<(String, Vec<String>), String> {
+            let message = request.get_message().as_ref().ok_or(String::from("Not a message"))?;
+            Ok((message.get_text().clone().unwrap_or_default(), Vec::new()))
+        }
+
+        fn dispatch(&self, method: &str, _args: Vec<String>, _request: &Request) -> Result<JsonValue, String> {
+            self.calls.borrow_mut().push(String::from("dispatch"));
+            match method {
+                "fail" => Err(String::from("failed")),
+                _ => Ok(JsonValue::from(method)),
+            }
+        }
+    }
+
+    struct Trace(&'static str);
+
+    impl Middleware<TestBot> for Trace {
+        fn before(&self, bot: &TestBot, _request: &Request) -> Option<Result<JsonValue, String>> {
+            bot.calls.borrow_mut().push(format!("before {}", self.0));
+            None
+        }
+
+        fn after(&self, bot: &TestBot, _request: &Request, result: Result<JsonValue, String>) -> Result<JsonValue, String> {
+            bot.calls.borrow_mut().push(format!("after {}", self.0));
+            //errors become a polite answer
+            result.or_else(|e| Ok(JsonValue::from(format!("sorry, {}", e))))
+        }
+    }
+
+    struct Banned(i64);
+
+    impl<B> Middleware<B> for Banned {
+        fn before(&self, _bot: &B, request: &Request) -> Option<Result<JsonValue, String>> {
+            match request.get_from() {
+                Some(user) if user.get_id() == self.0 => Some(Ok(JsonValue::Null)),
+                _ => None,
+            }
+        }
+    }
+
+    fn request(user_id: i64, text: &str) -> Request {
+        serde_json::from_str(&format!(r#"{{"update_id":1,"message":{{"message_id":1,"date":0,"chat":{{"id":1,"type":"private"}},"from":{{"is_bot":false,"id":{},"first_name":"Test"}},"text":"{}"}}}}"#, user_id, text)).unwrap()
+    }
+
+    #[test]
+    fn it_works() {
+        let bot = TestBot::new(Telegram::new("test"), &Arc::new(RwLock::new(TomlValue::from(""))), &Arc::new(RwLock::new(HashMap::new())));
+
+        assert_eq!(bot.parse(&request(1, "hello")), Ok(JsonValue::from("hello")));
+        assert_eq!(*bot.calls.borrow(), vec!["before outer", "before inner", "dispatch", "after inner", "after outer"]);
+        assert_eq!(*bot.logs.borrow(), vec!["test: update 1 (Some(Message)) handled"]);
+
+        bot.calls.borrow_mut().clear();
+        assert_eq!(bot.parse(&request(1, "fail")), Ok(JsonValue::from("sorry, failed")));
+
+        //banned users only go through the middlewares already run
+        bot.calls.borrow_mut().clear();
+        assert_eq!(bot.parse(&request(13, "hello")), Ok(JsonValue::Null));
+        assert_eq!(*bot.calls.borrow(), vec!["before outer", "after outer"]);
+    }
+}
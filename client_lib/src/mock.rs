@@ -0,0 +1,417 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use serde_json;
+use serde_json::value::Value as JsonValue;
+
+/// #MockField
+/// This object represents a single field received by MockServer.
+#[derive(Debug, Clone)]
+pub struct MockField {
+    filename: Option<String>,
+    content_type: Option<String>,
+    data: Vec<u8>,
+}
+
+impl MockField {
+    /// returns filename, present only on uploaded files
+    pub fn get_filename(&self) -> &Option<String> {
+        &self.filename
+    }
+
+    /// returns content type, if sent
+    pub fn get_content_type(&self) -> &Option<String> {
+        &self.content_type
+    }
+
+    /// returns raw data
+    pub fn get_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// returns data as text
+    pub fn get_text(&self) -> String {
+        String::from_utf8_lossy(&self.data).into_owned()
+    }
+}
+
+/// #MockCall
+/// This object represents a single Telegram bot API call received by MockServer.
+#[derive(Debug, Clone)]
+pub struct MockCall {
+    token: String,
+    method: String,
+    fields: HashMap<String, MockField>,
+}
+
+impl MockCall {
+    /// returns bot token
+    pub fn get_token(&self) -> &str {
+        &self.token
+    }
+
+    /// returns called method
+    pub fn get_method(&self) -> &str {
+        &self.method
+    }
+
+    /// returns all fields
+    pub fn get_fields(&self) -> &HashMap<String, MockField> {
+        &self.fields
+    }
+
+    /// returns a field
+    pub fn get_field(&self, name: &str) -> Option<&MockField> {
+        self.fields.get(name)
+    }
+
+    /// returns a field as text
+    pub fn get_text(&self, name: &str) -> Option<String> {
+        self.fields.get(name).map(|field| field.get_text())
+    }
+
+    /// checks if every given field has the given value
+    pub fn matches(&self, method: &str, fields: &[(&str, &str)]) -> bool {
+        self.method == method && fields.iter().all(|&(name, value)| self.get_text(name).map(|text| text == value).unwrap_or(false))
+    }
+}
+
+struct MockState {
+    calls: Vec<MockCall>,
+    responses: HashMap<String, VecDeque<JsonValue>>,
+    files: HashMap<String, Vec<u8>>,
+}
+
+/// #MockServer
+/// In-process HTTP server imitating Telegram bot APIs.
+/// Every call is recorded, with its fields, and answered with the scripted responses,
+/// or with a simple success if none is scripted for the called method.
+/// Point a bot to it using API_URL config value.
+pub struct MockServer {
+    url: String,
+    state: Arc<Mutex<MockState>>,
+    running: Arc<AtomicBool>,
+}
+
+impl MockServer {
+    /// starts a new server on a random local port
+    pub fn start() -> Result<MockServer, String> {
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(|e| format!("Unable to bind mock server: {:?}", e))?;
+        let addr = listener.local_addr().map_err(|e| format!("Unable to retrieve mock server address: {:?}", e))?;
+
+        let state = Arc::new(Mutex::new(MockState {
+            calls: Vec::new(),
+            responses: HashMap::new(),
+            files: HashMap::new(),
+        }));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread_state = state.clone();
+        let thread_running = running.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if !thread_running.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(s) => if let Err(e) = MockServer::handle(s, &thread_state) {
+                        println!("Mock server error: {}", e);
+                    },
+                    Err(e) => println!("Mock server connection error: {:?}", e),
+                }
+            }
+        });
+
+        Ok(MockServer {
+            url: format!("http://{}", addr),
+            state: state,
+            running: running,
+        })
+    }
+
+    /// returns server base URL, to be used as API_URL
+    pub fn get_url(&self) -> &str {
+        &self.url
+    }
+
+    /// scripts the response of the next call to the given method, responses are consumed in order
+    pub fn respond(&self, method: &str, response: JsonValue) {
+        if let Ok(mut state) = self.state.lock() {
+            state.responses.entry(method.to_owned()).or_insert(VecDeque::new()).push_back(response);
+        }
+    }
+
+    /// registers a file downloadable through /file/bot<token>/<file_path>
+    pub fn add_file(&self, file_path: &str, data: Vec<u8>) {
+        if let Ok(mut state) = self.state.lock() {
+            state.files.insert(file_path.to_owned(), data);
+        }
+    }
+
+    /// returns every recorded call, in order
+    pub fn get_calls(&self) -> Vec<MockCall> {
+        self.state.lock().map(|state| state.calls.clone()).unwrap_or(Vec::new())
+    }
+
+    /// returns recorded calls to the given method, in order
+    pub fn get_calls_to(&self, method: &str) -> Vec<MockCall> {
+        self.get_calls().into_iter().filter(|call| call.method == method).collect()
+    }
+
+    /// checks if a call to the given method was made with the given field values
+    pub fn was_called(&self, method: &str, fields: &[(&str, &str)]) -> bool {
+        self.get_calls().iter().any(|call| call.matches(method, fields))
+    }
+
+    /// forgets every recorded call
+    pub fn clear(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.calls.clear();
+        }
+    }
+
+    /// serves a single connection
+    fn handle(stream: TcpStream, state: &Arc<Mutex<MockState>>) -> Result<(), String> {
+        let mut reader = BufReader::new(stream.try_clone().map_err(|e| format!("Unable to clone stream: {:?}", e))?);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).map_err(|e| format!("Unable to read request line: {:?}", e))?;
+        let path = request_line.split(' ').nth(1).unwrap_or("").to_owned();
+
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).map_err(|e| format!("Unable to read header: {:?}", e))?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(pos) = line.find(':') {
+                headers.insert(line[..pos].trim().to_lowercase(), line[pos + 1..].trim().to_owned());
+            }
+        }
+
+        let body = MockServer::read_body(&mut reader, &headers)?;
+
+        let (status, response) = {
+            let mut state = state.lock().map_err(|e| format!("Unable to lock mock state: {:?}", e))?;
+            MockServer::route(&mut state, &path, &headers, &body)
+        };
+
+        let mut stream = stream;
+        write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, response.len())
+            .and_then(|_| stream.write_all(&response))
+            .map_err(|e| format!("Unable to write response: {:?}", e))
+    }
+
+    /// reads request body, both with Content-Length and chunked encoding
+    fn read_body<R: BufRead>(reader: &mut R, headers: &HashMap<String, String>) -> Result<Vec<u8>, String> {
+        let mut body = Vec::new();
+        if headers.get("transfer-encoding").map(|v| v.to_lowercase().contains("chunked")).unwrap_or(false) {
+            loop {
+                let mut size_line = String::new();
+                reader.read_line(&mut size_line).map_err(|e| format!("Unable to read chunk size: {:?}", e))?;
+                let size = usize::from_str_radix(size_line.trim().split(';').next().unwrap_or(""), 16)
+                    .map_err(|e| format!("Invalid chunk size {}: {:?}", size_line, e))?;
+                let mut chunk = vec![0u8; size + 2];
+                reader.read_exact(&mut chunk).map_err(|e| format!("Unable to read chunk: {:?}", e))?;
+                if size == 0 {
+                    break;
+                }
+                body.extend_from_slice(&chunk[..size]);
+            }
+        }
+        else if let Some(length) = headers.get("content-length").and_then(|v| v.parse::<usize>().ok()) {
+            body.resize(length, 0);
+            reader.read_exact(&mut body).map_err(|e| format!("Unable to read body: {:?}", e))?;
+        }
+        Ok(body)
+    }
+
+    /// records the call and chooses the response
+    fn route(state: &mut MockState, path: &str, headers: &HashMap<String, String>, body: &[u8]) -> (&'static str, Vec<u8>) {
+        let parts: Vec<&str> = path.trim_start_matches('/').splitn(3, '/').collect();
+        match parts.as_slice() {
+            &["file", token, file_path] if token.starts_with("bot") => match state.files.get(file_path) {
+                Some(data) => ("200 OK", data.clone()),
+                None => ("404 Not Found", MockServer::error(404, "Not Found")),
+            },
+            &[token, method] if token.starts_with("bot") => {
+                let fields = match headers.get("content-type") {
+                    Some(content_type) if content_type.starts_with("multipart/form-data") => MockServer::parse_multipart(content_type, body),
+                    Some(content_type) if content_type.starts_with("application/json") => MockServer::parse_json(body),
+                    _ => HashMap::new(),
+                };
+                state.calls.push(MockCall {
+                    token: token[3..].to_owned(),
+                    method: method.to_owned(),
+                    fields: fields,
+                });
+                let response = state.responses.get_mut(method)
+                    .and_then(|queue| queue.pop_front())
+                    .unwrap_or(json_ok());
+                ("200 OK", response.to_string().into_bytes())
+            },
+            _ => ("404 Not Found", MockServer::error(404, "Not Found")),
+        }
+    }
+
+    /// parses a multipart/form-data body
+    fn parse_multipart(content_type: &str, body: &[u8]) -> HashMap<String, MockField> {
+        let mut fields = HashMap::new();
+        let boundary = match content_type.split(';').map(|s| s.trim()).find(|s| s.starts_with("boundary=")) {
+            Some(s) => format!("--{}", s["boundary=".len()..].trim_matches('"')),
+            None => { return fields; },
+        };
+
+        for part in split_bytes(body, boundary.as_bytes()) {
+            //every part starts with CRLF and ends with CRLF, the last one with "--"
+            let part = if part.starts_with(b"\r\n") { &part[2..] } else { part };
+            let head_end = match find_bytes(part, b"\r\n\r\n") {
+                Some(pos) => pos,
+                None => continue,
+            };
+            let head = String::from_utf8_lossy(&part[..head_end]).into_owned();
+            let mut data = &part[head_end + 4..];
+            if data.ends_with(b"\r\n") {
+                data = &data[..data.len() - 2];
+            }
+
+            let mut name = None;
+            let mut filename = None;
+            let mut part_type = None;
+            for line in head.split("\r\n") {
+                let lower = line.to_lowercase();
+                if lower.starts_with("content-disposition:") {
+                    for attr in line.split(';').map(|s| s.trim()) {
+                        if attr.starts_with("name=") {
+                            name = Some(attr["name=".len()..].trim_matches('"').to_owned());
+                        }
+                        else if attr.starts_with("filename=") {
+                            filename = Some(attr["filename=".len()..].trim_matches('"').to_owned());
+                        }
+                    }
+                }
+                else if lower.starts_with("content-type:") {
+                    part_type = Some(line["content-type:".len()..].trim().to_owned());
+                }
+            }
+
+            if let Some(n) = name {
+                fields.insert(n, MockField {
+                    filename: filename,
+                    content_type: part_type,
+                    data: data.to_vec(),
+                });
+            }
+        }
+
+        fields
+    }
+
+    /// parses an application/json body, non string values are kept json encoded
+    fn parse_json(body: &[u8]) -> HashMap<String, MockField> {
+        match serde_json::from_slice::<JsonValue>(body) {
+            Ok(JsonValue::Object(map)) => map.into_iter()
+                .map(|(k, v)| (k, MockField {
+                    filename: None,
+                    content_type: None,
+                    data: match v {
+                        JsonValue::String(s) => s.into_bytes(),
+                        other => other.to_string().into_bytes(),
+                    },
+                }))
+                .collect(),
+            _ => HashMap::new(),
+        }
+    }
+
+    /// builds a Telegram error response
+    fn error(code: u64, description: &str) -> Vec<u8> {
+        json_error(code, description).to_string().into_bytes()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        //wake up the listener so that it notices the shutdown
+        let _ = TcpStream::connect(&self.url["http://".len()..]);
+    }
+}
+
+/// builds a generic Telegram success response
+pub fn json_ok() -> JsonValue {
+    json_result(JsonValue::Bool(true))
+}
+
+/// builds a Telegram success response with the given result
+pub fn json_result(result: JsonValue) -> JsonValue {
+    let mut map = serde_json::Map::new();
+    map.insert(String::from("ok"), JsonValue::Bool(true));
+    map.insert(String::from("result"), result);
+    JsonValue::Object(map)
+}
+
+/// builds a Telegram error response
+pub fn json_error(code: u64, description: &str) -> JsonValue {
+    let mut map = serde_json::Map::new();
+    map.insert(String::from("ok"), JsonValue::Bool(false));
+    map.insert(String::from("error_code"), JsonValue::from(code));
+    map.insert(String::from("description"), JsonValue::String(description.to_owned()));
+    JsonValue::Object(map)
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.len() > haystack.len() {
+        return None;
+    }
+    (0..haystack.len() - needle.len() + 1).find(|&i| &haystack[i..i + needle.len()] == needle)
+}
+
+fn split_bytes<'a>(haystack: &'a [u8], separator: &[u8]) -> Vec<&'a [u8]> {
+    let mut parts = Vec::new();
+    let mut rest = haystack;
+    while let Some(pos) = find_bytes(rest, separator) {
+        parts.push(&rest[..pos]);
+        rest = &rest[pos + separator.len()..];
+    }
+    parts.push(rest);
+    //skip preamble
+    parts.into_iter().skip(1).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MockServer, json_result};
+    use super::super::Telegram;
+    use super::super::entities::InputFile;
+    use serde_json::value::Value as JsonValue;
+
+    #[test]
+    fn it_works() {
+        let server = MockServer::start().unwrap();
+        server.respond("sendMessage", json_result(JsonValue::from(42)));
+
+        let mut client = Telegram::new("test");
+        client.api_url = server.get_url().to_owned();
+
+        assert_eq!(client.send_message("1111111", "prova", None, None, None, None).unwrap()["result"], JsonValue::from(42));
+        assert_eq!(client.send_message("1111111", "again", None, None, None, None).unwrap()["result"], JsonValue::Bool(true));
+        client.send_document("1111111", InputFile::Bytes { name: String::from("a.txt"), mime: None, data: b"content".to_vec() }, None, None).unwrap();
+
+        assert!(server.was_called("sendMessage", &[("chat_id", "1111111"), ("text", "prova")]));
+        assert!(!server.was_called("sendMessage", &[("chat_id", "2222222")]));
+
+        let calls = server.get_calls();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[0].get_token(), "test");
+        let document = calls[2].get_field("document").unwrap();
+        assert_eq!(document.get_filename(), &Some(String::from("a.txt")));
+        assert_eq!(document.get_data(), b"content");
+    }
+}
@@ -0,0 +1,290 @@
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use serde_json::value::Value as JsonValue;
+
+use toml::Value as TomlValue;
+
+use entities::{ENTITY_HASHTAG, Message, Request};
+
+/// handles a command: receives the bot, the command arguments and the whole Request
+pub type CommandHandler<B> = Box<dyn Fn(&B, &Args, &Request) -> Result<JsonValue, String>>;
+
+struct Command<B> {
+    name: String,
+    usage: String,
+    description: String,
+    handler: CommandHandler<B>,
+}
+
+/// #Args
+/// Command arguments, with typed extraction
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    args: Vec<String>,
+}
+
+impl Args {
+    /// wraps already split arguments
+    pub fn new(args: Vec<String>) -> Args {
+        Args {
+            args: args,
+        }
+    }
+
+    /// returns a mandatory argument, converted to the requested type
+    pub fn get<T: FromStr>(&self, index: usize, name: &str) -> Result<T, String> {
+        self.optional(index, name)?.ok_or(format!("Missing argument {}", name))
+    }
+
+    /// returns an optional argument, converted to the requested type
+    pub fn optional<T: FromStr>(&self, index: usize, name: &str) -> Result<Option<T>, String> {
+        match self.args.get(index) {
+            Some(value) => value.parse::<T>().map(Some).map_err(|_| format!("Invalid argument {}: {}", name, value)),
+            None => Ok(None),
+        }
+    }
+
+    /// returns every argument from index on, joined by spaces
+    pub fn rest(&self, index: usize) -> String {
+        if index < self.args.len() { self.args[index..].join(" ") } else { String::new() }
+    }
+
+    /// returns arguments count
+    pub fn len(&self) -> usize {
+        self.args.len()
+    }
+
+    /// checks if there are no arguments
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+
+    /// returns raw arguments
+    pub fn get_args(&self) -> &Vec<String> {
+        &self.args
+    }
+}
+
+/// splits command arguments on whitespaces, single or double quotes group words into a single argument
+/// and backslash escapes the next char, an unterminated quote lasts until the end of text
+pub fn split_arguments(text: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    //an empty quoted argument is still an argument
+    let mut started = false;
+    let mut quote: Option<char> = None;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => { quote = None; },
+            (Some('"'), '\\') | (None, '\\') => match chars.next() {
+                Some(escaped) => {
+                    current.push(escaped);
+                    started = true;
+                },
+                None => current.push(c),
+            },
+            (Some(_), c) => current.push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                started = true;
+            },
+            (None, c) if c.is_whitespace() => if started || !current.is_empty() {
+                args.push(current);
+                current = String::new();
+                started = false;
+            },
+            (None, c) => current.push(c),
+        }
+    }
+    if started || !current.is_empty() {
+        args.push(current);
+    }
+    args
+}
+
+/// #Router
+/// Maps commands to handlers, replacing hand-written Bot::dispatch match blocks.
+/// Commands addressed to another bot, like /cmd@OtherBot in groups, are ignored,
+/// using BOT_NAME config value to recognize the bot's own ones.
+pub struct Router<B> {
+    bot_name: Option<String>,
+    prefixes: Vec<char>,
+    commands: Vec<Command<B>>,
+}
+
+impl<B> Router<B> {
+    /// creates an empty router, recognizing commands starting with /
+    pub fn new() -> Router<B> {
+        Router {
+            bot_name: None,
+            prefixes: vec!['/'],
+            commands: Vec::new(),
+        }
+    }
+
+    /// creates an empty router, reading bot name from BOT_NAME config value, if present
+    pub fn from_config(config: &Arc<RwLock<TomlValue>>) -> Router<B> {
+        let router = Router::new();
+        match config.read().ok().and_then(|c| c.get("BOT_NAME").and_then(|n| n.as_str()).map(String::from)) {
+            Some(name) => router.bot_name(&name),
+            None => router,
+        }
+    }
+
+    /// sets bot name, with or without leading @
+    pub fn bot_name(mut self, name: &str) -> Router<B> {
+        self.bot_name = Some(name.trim_start_matches('@').to_owned());
+        self
+    }
+
+    /// sets the chars commands can start with
+    pub fn prefixes(mut self, prefixes: &[char]) -> Router<B> {
+        self.prefixes = prefixes.to_vec();
+        self
+    }
+
+    /// registers a command, usage and description are shown in help text
+    pub fn command<F>(mut self, name: &str, usage: &str, description: &str, handler: F) -> Router<B>
+        where F: Fn(&B, &Args, &Request) -> Result<JsonValue, String> + 'static
+    {
+        self.commands.push(Command {
+            name: name.to_owned(),
+            usage: usage.to_owned(),
+            description: description.to_owned(),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// extracts command name and arguments from a message, ready for Bot::parse_message
+    /// commands are recognized by Telegram's bot_command entities, or hashtag ones if # is a prefix,
+    /// so that pasted paths like /usr/bin aren't commands
+    /// returns None if text doesn't start with a command, or it's addressed to another bot
+    pub fn parse_message(&self, message: &Message) -> Option<(String, Vec<String>)> {
+        let entity = if self.prefixes.contains(&'/') { message.get_command() } else { None };
+        let entity = match entity {
+            Some(e) => Some(e),
+            None => if self.prefixes.contains(&'#') { message.get_leading_entity(ENTITY_HASHTAG) } else { None },
+        };
+        entity.and_then(|(command, args)| self.parse_command(command, args))
+    }
+
+    /// extracts command name and arguments from a text, ready for Bot::dispatch
+    /// prefer parse_message when a Message is available, see it for details
+    /// returns None if text isn't a command, or it's addressed to another bot
+    pub fn parse(&self, text: &str) -> Option<(String, Vec<String>)> {
+        let text = text.trim();
+        let (word, args) = match text.find(char::is_whitespace) {
+            Some(pos) => (&text[..pos], &text[pos..]),
+            None => (text, ""),
+        };
+        self.parse_command(word, args)
+    }
+
+    /// splits a prefixed command word into name and bot name, then splits arguments
+    fn parse_command(&self, word: &str, args: &str) -> Option<(String, Vec<String>)> {
+        let first = match word.chars().next() {
+            Some(c) => c,
+            None => { return None; },
+        };
+        if !self.prefixes.contains(&first) {
+            return None;
+        }
+
+        let mut parts = word[first.len_utf8()..].splitn(2, '@');
+        let name = parts.next().unwrap_or("");
+        match (parts.next(), &self.bot_name) {
+            (Some(target), &Some(ref bot_name)) if !target.eq_ignore_ascii_case(bot_name) => { return None; },
+            _ => {},
+        }
+        if name.is_empty() {
+            return None;
+        }
+
+        Some((name.to_owned(), split_arguments(args)))
+    }
+
+    /// calls the handler registered for the command
+    pub fn dispatch(&self, bot: &B, method: &str, args: Vec<String>, request: &Request) -> Result<JsonValue, String> {
+        match self.commands.iter().find(|c| c.name == method) {
+            Some(command) => (command.handler)(bot, &Args::new(args), request),
+            None => Err(format!("Method {} not found", method)),
+        }
+    }
+
+    /// checks if a command is registered
+    pub fn has_command(&self, method: &str) -> bool {
+        self.commands.iter().any(|c| c.name == method)
+    }
+
+    /// generates help text, one line per command in registration order
+    pub fn help_text(&self) -> String {
+        let prefix = self.prefixes.first().cloned().unwrap_or('/');
+        self.commands.iter()
+            .map(|c| {
+                let mut line = format!("{}{}", prefix, c.name);
+                if !c.usage.is_empty() {
+                    line.push(' ');
+                    line.push_str(&c.usage);
+                }
+                if !c.description.is_empty() {
+                    line.push_str(" - ");
+                    line.push_str(&c.description);
+                }
+                line
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Args, Router, split_arguments};
+    use entities::{Message, Request};
+    use serde_json;
+    use serde_json::value::Value as JsonValue;
+    use std::sync::{Arc, RwLock};
+    use toml;
+
+    struct TestBot {
+        greeting: String,
+    }
+
+    #[test]
+    fn it_works() {
+        assert_eq!(split_arguments("  one   \"two three\" 'four \"five\"' six\\ seven \"\""), vec!["one", "two three", "four \"five\"", "six seven", ""]);
+
+        let config = Arc::new(RwLock::new(toml::from_str::<toml::Value>("BOT_NAME = \"@TestBot\"").unwrap()));
+        let router = Router::from_config(&config)
+            .command("greet", "<name> [times]", "greets someone", |bot: &TestBot, args, _request| {
+                let name: String = args.get(0, "name")?;
+                let times: u32 = args.optional(1, "times")?.unwrap_or(1);
+                Ok(JsonValue::from(vec![format!("{} {}", bot.greeting, name); times as usize]))
+            })
+            .command("help", "", "shows this help", |_bot: &TestBot, _args, _request| Ok(JsonValue::Null));
+
+        assert_eq!(router.parse("/greet@testbot  pippo 2"), Some((String::from("greet"), vec![String::from("pippo"), String::from("2")])));
+        assert_eq!(router.parse("/greet@OtherBot pippo"), None);
+        assert_eq!(router.parse("#greet pippo"), None);
+        assert_eq!(router.parse("hello"), None);
+        assert_eq!(router.parse("/"), None);
+
+        //entities mark commands, pasted paths aren't
+        let message = |text: &str, entities: &str| serde_json::from_str::<Message>(&format!(r#"{{"message_id":1,"date":0,"chat":{{"id":1,"type":"private"}},"from":{{"is_bot":false,"id":1,"first_name":"Test"}},"text":"{}","entities":[{}]}}"#, text, entities)).unwrap();
+        assert_eq!(router.parse_message(&message("/greet@TestBot pippo", r#"{"type":"bot_command","offset":0,"length":14}"#)), Some((String::from("greet"), vec![String::from("pippo")])));
+        assert_eq!(router.parse_message(&message("/usr/bin pippo", "")), None);
+
+        let bot = TestBot { greeting: String::from("Hello") };
+        let request: Request = serde_json::from_str("{}").unwrap();
+        assert_eq!(router.dispatch(&bot, "greet", vec![String::from("pippo"), String::from("2")], &request), Ok(JsonValue::from(vec!["Hello pippo", "Hello pippo"])));
+        assert_eq!(router.dispatch(&bot, "greet", vec![String::from("pippo"), String::from("many")], &request), Err(String::from("Invalid argument times: many")));
+        assert_eq!(router.dispatch(&bot, "greet", Vec::new(), &request), Err(String::from("Missing argument name")));
+        assert!(router.dispatch(&bot, "missing", Vec::new(), &request).is_err());
+
+        assert_eq!(router.help_text(), "/greet <name> [times] - greets someone\n/help - shows this help");
+        assert_eq!(Args::new(vec![String::from("a"), String::from("b")]).rest(1), "b");
+    }
+}
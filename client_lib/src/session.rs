@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use serde_json;
+use serde_json::value::Value as JsonValue;
+
+/// prefix of every session key managed by Session, other keys are left untouched
+pub const SCOPE_PREFIX: &'static str = "scope:";
+
+/// field holding the stored value
+const VALUE: &'static str = "value";
+/// field holding the expiration time, in seconds since UNIX epoch
+const EXPIRES: &'static str = "expires";
+
+/// #Session
+/// Typed view over bot's raw session, where data lives in separate namespaces:
+/// one for every chat, one for every user and a global one.
+/// Values are (de)serialized with serde and can expire, expired entries are never returned
+/// and are periodically removed by the server (see collect_garbage).
+pub struct Session {
+    session: Arc<RwLock<HashMap<String, JsonValue>>>,
+}
+
+impl Session {
+    /// wraps the session received by Bot::new
+    pub fn new(session: &Arc<RwLock<HashMap<String, JsonValue>>>) -> Session {
+        Session {
+            session: session.clone(),
+        }
+    }
+
+    /// data belonging to a chat
+    pub fn chat(&self, chat_id: i64) -> Scope {
+        self.scope(format!("{}chat:{}", SCOPE_PREFIX, chat_id))
+    }
+
+    /// data belonging to a user, shared between every chat
+    pub fn user(&self, user_id: i64) -> Scope {
+        self.scope(format!("{}user:{}", SCOPE_PREFIX, user_id))
+    }
+
+    /// data shared by the whole bot
+    pub fn global(&self) -> Scope {
+        self.scope(format!("{}global", SCOPE_PREFIX))
+    }
+
+    fn scope(&self, key: String) -> Scope {
+        Scope {
+            session: self.session.clone(),
+            key: key,
+        }
+    }
+}
+
+/// #Scope
+/// A session namespace, see Session
+pub struct Scope {
+    session: Arc<RwLock<HashMap<String, JsonValue>>>,
+    key: String,
+}
+
+impl Scope {
+    /// retrieves a value, None if missing or expired
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, String> {
+        let session = self.session.read().map_err(|e| format!("Error read locking session: {:?}", e))?;
+        match session.get(&self.key).and_then(|scope| scope.get(key)) {
+            Some(entry) => if is_expired(entry, now()) {
+                Ok(None)
+            }
+            else {
+                match entry.get(VALUE) {
+                    Some(value) => serde_json::from_value(value.clone()).map(Some).map_err(|e| format!("Unable to deserialize session value {}: {:?}", key, e)),
+                    None => Ok(None),
+                }
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// stores a value, without expiration
+    pub fn set<T: Serialize>(&self, key: &str, value: &T) -> Result<(), String> {
+        self.store(key, value, None)
+    }
+
+    /// stores a value, that expires after ttl
+    pub fn set_with_ttl<T: Serialize>(&self, key: &str, value: &T, ttl: Duration) -> Result<(), String> {
+        self.store(key, value, Some(now() + ttl.as_secs()))
+    }
+
+    /// removes a value
+    pub fn remove(&self, key: &str) -> Result<(), String> {
+        let mut session = self.session.write().map_err(|e| format!("Error write locking session: {:?}", e))?;
+        let empty = match session.get_mut(&self.key) {
+            Some(&mut JsonValue::Object(ref mut scope)) => {
+                scope.remove(key);
+                scope.is_empty()
+            },
+            _ => false,
+        };
+        if empty {
+            session.remove(&self.key);
+        }
+        Ok(())
+    }
+
+    /// removes every value in this scope
+    pub fn clear(&self) -> Result<(), String> {
+        let mut session = self.session.write().map_err(|e| format!("Error write locking session: {:?}", e))?;
+        session.remove(&self.key);
+        Ok(())
+    }
+
+    fn store<T: Serialize>(&self, key: &str, value: &T, expires: Option<u64>) -> Result<(), String> {
+        let mut entry = serde_json::Map::new();
+        entry.insert(String::from(VALUE), serde_json::to_value(value).map_err(|e| format!("Unable to serialize session value {}: {:?}", key, e))?);
+        match expires {
+            Some(e) => { entry.insert(String::from(EXPIRES), JsonValue::from(e)); },
+            None => {},
+        }
+
+        let mut session = self.session.write().map_err(|e| format!("Error write locking session: {:?}", e))?;
+        let scope = session.entry(self.key.clone()).or_insert_with(|| JsonValue::Object(serde_json::Map::new()));
+        if !scope.is_object() {
+            *scope = JsonValue::Object(serde_json::Map::new());
+        }
+        match scope {
+            &mut JsonValue::Object(ref mut map) => { map.insert(key.to_owned(), JsonValue::Object(entry)); },
+            _ => {},
+        }
+        Ok(())
+    }
+}
+
+/// removes expired entries, and emptied scopes, from a raw session
+/// returns the number of removed entries
+pub fn collect_garbage(session: &mut HashMap<String, JsonValue>) -> usize {
+    let now = now();
+    let mut removed = 0;
+    let mut empty = Vec::new();
+    for (key, scope) in session.iter_mut() {
+        if !key.starts_with(SCOPE_PREFIX) {
+            continue;
+        }
+        match scope {
+            &mut JsonValue::Object(ref mut map) => {
+                let expired: Vec<String> = map.iter().filter(|&(_, entry)| is_expired(entry, now)).map(|(k, _)| k.clone()).collect();
+                for k in expired {
+                    map.remove(&k);
+                    removed += 1;
+                }
+                if map.is_empty() {
+                    empty.push(key.clone());
+                }
+            },
+            _ => {},
+        }
+    }
+    for key in empty {
+        session.remove(&key);
+    }
+    removed
+}
+
+fn is_expired(entry: &JsonValue, now: u64) -> bool {
+    match entry.get(EXPIRES).and_then(|e| e.as_u64()) {
+        Some(expires) => expires <= now,
+        None => false,
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Session, collect_garbage, serde_json};
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+    use std::time::Duration;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Position {
+        latitude: f64,
+        longitude: f64,
+    }
+
+    #[test]
+    fn it_works() {
+        let raw = Arc::new(RwLock::new(HashMap::new()));
+        let session = Session::new(&raw);
+
+        let position = Position { latitude: 45.5589, longitude: 12.233439 };
+        session.chat(1).set("position", &position).unwrap();
+        assert_eq!(session.chat(1).get::<Position>("position").unwrap(), Some(position));
+        //scopes don't overlap
+        assert_eq!(session.user(1).get::<Position>("position").unwrap(), None);
+        assert_eq!(session.chat(2).get::<Position>("position").unwrap(), None);
+        assert!(session.chat(1).get::<String>("position").is_err());
+
+        session.global().set("counter", &3).unwrap();
+        session.global().set_with_ttl("expired", &true, Duration::from_secs(0)).unwrap();
+        session.global().set_with_ttl("alive", &true, Duration::from_secs(3600)).unwrap();
+        session.user(5).set_with_ttl("expired", &true, Duration::from_secs(0)).unwrap();
+        assert_eq!(session.global().get::<bool>("expired").unwrap(), None);
+        assert_eq!(session.global().get::<bool>("alive").unwrap(), Some(true));
+
+        //foreign keys are left untouched
+        raw.write().unwrap().insert(String::from("file_id_cache"), serde_json::from_str(r#"{"expires": 0}"#).unwrap());
+        assert_eq!(collect_garbage(&mut raw.write().unwrap()), 2);
+        assert!(raw.read().unwrap().get("scope:user:5").is_none());
+        assert!(raw.read().unwrap().get("file_id_cache").is_some());
+        assert_eq!(session.global().get::<i32>("counter").unwrap(), Some(3));
+
+        session.chat(1).remove("position").unwrap();
+        assert!(raw.read().unwrap().get("scope:chat:1").is_none());
+        session.global().clear().unwrap();
+        assert_eq!(session.global().get::<i32>("counter").unwrap(), None);
+    }
+}
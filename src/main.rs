@@ -8,11 +8,13 @@
 //! Provides a C ABI to call on dynamically linked libs, which are
 //! dynamicaly reloaded on file change,
 //! allowing centralized configuration with automatical file reload
-//! and in-memory per-bot private session.
+//! and per-bot private session, optionally persisted on disk.
 
 #[macro_use]
 extern crate lazy_static;
+extern crate futures;
 extern crate hyper;
+extern crate signal_hook;
 extern crate native_tls;
 extern crate tokio_tls;
 extern crate tokio_proto;
@@ -24,8 +26,14 @@ use std::env;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::process;
+use std::thread;
+
+use futures::Future;
+use futures::sync::oneshot;
 
 use hyper::server::Http;
+use signal_hook::iterator::Signals;
 use native_tls::{TlsAcceptor, Pkcs12};
 use tokio_proto::TcpServer;
 use tokio_tls::proto;
@@ -59,6 +67,28 @@ fn main() {
             config["port"].as_str().expect("Error interpreting port value")
         }).parse().expect("Error parsing webserver address");
 
+    let webserver = WebServer::new(&config).expect("Error interpreting webserver config");
+
+    //on SIGTERM/SIGINT stop accepting webhooks, wait running bots and save sessions
+    let signals = Signals::new(&[signal_hook::SIGTERM, signal_hook::SIGINT]).expect("Unable to register signal handlers");
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let stopping = webserver.clone();
+    thread::spawn(move || {
+        match signals.forever().next() {
+            Some(signal) => println!("Received signal {}, shutting down", signal),
+            None => {},
+        }
+        stopping.stop_accepting();
+        if https {
+            //tokio-proto server can't be stopped, so shutdown completes here
+            stopping.shutdown();
+            process::exit(0);
+        }
+        else {
+            let _ = stop_tx.send(());
+        }
+    });
+
     if https {
         // Create our TLS context through which new connections will be
         // accepted. This is where we pass in the certificate as well to
@@ -78,11 +108,14 @@ fn main() {
         // Finally use `tokio-proto`'s `TcpServer` helper struct to quickly
         // take our protocol above to running our Service on a local TCP port.
         let srv = TcpServer::new(proto, addr);
-        srv.serve(|| Ok(WebServer));
+        srv.serve(move || Ok(webserver.clone()));
     }
     else {
         //start normal webserver
-        let server = Http::new().bind(&addr, || Ok(WebServer)).expect("Error on webserver init");
-        server.run().expect("Error on webserver run");
+        let service = webserver.clone();
+        let server = Http::new().bind(&addr, move || Ok(service.clone())).expect("Error on webserver init");
+        //stops listening on signal, giving open connections a chance to complete
+        server.run_until(stop_rx.map_err(|_| ())).expect("Error on webserver run");
+        webserver.shutdown();
     }
 }
@@ -0,0 +1,262 @@
+extern crate toml;
+
+use std::collections::HashMap;
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use self::toml::Value as TomlValue;
+
+/// default maximum request body size, Telegram updates are far smaller
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 1024 * 1024;
+/// default seconds allowed to receive the whole request body
+pub const DEFAULT_READ_TIMEOUT: u64 = 10;
+/// default seconds allowed to a bot to handle an update, Telegram itself gives up after 60
+pub const DEFAULT_EXECUTION_TIMEOUT: u64 = 30;
+/// default number of recent update_ids remembered to detect duplicates
+pub const DEFAULT_DEDUP_WINDOW: u64 = 100;
+/// default number of updates a bot can handle at the same time, Telegram itself opens up to 40 connections
+pub const DEFAULT_MAX_CONCURRENCY: u64 = 8;
+
+/// per-bot webserver limits
+#[derive(Debug, Clone, PartialEq)]
+pub struct BotSettings {
+    max_body_size: u64,
+    read_timeout: Duration,
+    execution_timeout: Duration,
+    dedup_window: usize,
+    max_concurrency: usize,
+}
+
+impl BotSettings {
+    /// reads settings from a toml table, falling back to the given defaults
+    fn from_config(config: Option<&TomlValue>, name: &str, defaults: &BotSettings) -> Result<BotSettings, String> {
+        let mut settings = defaults.clone();
+        let config = match config {
+            Some(c) => c,
+            None => { return Ok(settings); },
+        };
+
+        match config.get("max_body_size") {
+            Some(value) => {
+                settings.max_body_size = BotSettings::as_u64(value).ok_or(format!("Error interpreting {}max_body_size value", name))?;
+            },
+            None => {},
+        }
+
+        match config.get("read_timeout") {
+            Some(value) => {
+                settings.read_timeout = Duration::from_secs(BotSettings::as_u64(value).ok_or(format!("Error interpreting {}read_timeout value", name))?);
+            },
+            None => {},
+        }
+
+        match config.get("execution_timeout") {
+            Some(value) => {
+                settings.execution_timeout = Duration::from_secs(BotSettings::as_u64(value).ok_or(format!("Error interpreting {}execution_timeout value", name))?);
+            },
+            None => {},
+        }
+
+        match config.get("dedup_window") {
+            Some(value) => {
+                settings.dedup_window = BotSettings::as_u64(value).ok_or(format!("Error interpreting {}dedup_window value", name))? as usize;
+            },
+            None => {},
+        }
+
+        match config.get("max_concurrency") {
+            Some(value) => match BotSettings::as_u64(value) {
+                Some(n) if n > 0 => { settings.max_concurrency = n as usize; },
+                _ => { return Err(format!("Error interpreting {}max_concurrency value", name)); },
+            },
+            None => {},
+        }
+
+        Ok(settings)
+    }
+
+    fn as_u64(value: &TomlValue) -> Option<u64> {
+        value.as_integer().and_then(|v| if v >= 0 { Some(v as u64) } else { None })
+    }
+
+    /// returns maximum request body size
+    pub fn get_max_body_size(&self) -> u64 {
+        self.max_body_size
+    }
+
+    /// returns time allowed to receive the whole request body
+    pub fn get_read_timeout(&self) -> Duration {
+        self.read_timeout
+    }
+
+    /// returns time allowed to the bot to handle an update
+    pub fn get_execution_timeout(&self) -> Duration {
+        self.execution_timeout
+    }
+
+    /// returns number of recent update_ids remembered, 0 disables duplicates detection
+    pub fn get_dedup_window(&self) -> usize {
+        self.dedup_window
+    }
+
+    /// returns number of updates the bot can handle at the same time
+    pub fn get_max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+}
+
+/// a running bot thread, counted against its max_concurrency until dropped
+pub struct Slot {
+    running: Arc<AtomicUsize>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Allowlist of enabled bots, configured in the bots section of rustegram.toml
+/// If no bot is explicitly enabled, every dylib found in bots folder at startup is enabled.
+pub struct Bots {
+    bots: HashMap<String, BotSettings>,
+    running: HashMap<String, Arc<AtomicUsize>>,
+}
+
+impl Bots {
+    /// reads enabled bots and their limits from server config
+    pub fn from_config(config: &TomlValue, bots_dir: &Path) -> Result<Bots, String> {
+        let section = config.get("bots");
+        let defaults = BotSettings::from_config(section, "bots.", &BotSettings {
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            read_timeout: Duration::from_secs(DEFAULT_READ_TIMEOUT),
+            execution_timeout: Duration::from_secs(DEFAULT_EXECUTION_TIMEOUT),
+            dedup_window: DEFAULT_DEDUP_WINDOW as usize,
+            max_concurrency: DEFAULT_MAX_CONCURRENCY as usize,
+        })?;
+
+        let names = match section.and_then(|s| s.get("enabled")) {
+            Some(value) => {
+                let mut names = Vec::new();
+                for name in value.as_array().ok_or(String::from("Error interpreting bots.enabled value"))? {
+                    let n = name.as_str().ok_or(String::from("Error interpreting bots.enabled value"))?;
+                    if !Bots::is_valid_name(n) {
+                        return Err(format!("Invalid bot name {} in bots.enabled", n));
+                    }
+                    names.push(n.to_owned());
+                }
+                names
+            },
+            None => Bots::scan(bots_dir)?,
+        };
+
+        let mut bots = HashMap::new();
+        let mut running = HashMap::new();
+        for name in names {
+            let settings = BotSettings::from_config(section.and_then(|s| s.get(&name)), &format!("bots.{}.", name), &defaults)?;
+            running.insert(name.clone(), Arc::new(AtomicUsize::new(0)));
+            bots.insert(name, settings);
+        }
+
+        Ok(Bots {
+            bots: bots,
+            running: running,
+        })
+    }
+
+    /// returns settings of an enabled bot, None if the bot isn't enabled
+    pub fn get(&self, name: &str) -> Option<&BotSettings> {
+        self.bots.get(name)
+    }
+
+    /// reserves a bot thread, None if the bot already runs max_concurrency updates
+    /// threads of timed out runs keep their slot until they really end, so a hung bot can't pile them up
+    pub fn acquire(&self, name: &str) -> Option<Slot> {
+        match (self.bots.get(name), self.running.get(name)) {
+            (Some(settings), Some(running)) => if running.fetch_add(1, Ordering::SeqCst) < settings.max_concurrency {
+                Some(Slot {
+                    running: running.clone(),
+                })
+            }
+            else {
+                running.fetch_sub(1, Ordering::SeqCst);
+                None
+            },
+            _ => None,
+        }
+    }
+
+    /// bot names end up in file paths, so only plain identifiers are allowed
+    fn is_valid_name(name: &str) -> bool {
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    }
+
+    /// finds every bot dylib inside bots folder
+    fn scan(bots_dir: &Path) -> Result<Vec<String>, String> {
+        let entries = fs::read_dir(bots_dir).map_err(|e| format!("Unable to read bots dir {:?}: {:?}", bots_dir, e))?;
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Unable to read bots dir {:?}: {:?}", bots_dir, e))?;
+            match entry.file_name().to_str() {
+                Some(file_name) => if file_name.starts_with(DLL_PREFIX) && file_name.ends_with(DLL_SUFFIX) && file_name.len() > DLL_PREFIX.len() + DLL_SUFFIX.len() {
+                    let name = &file_name[DLL_PREFIX.len()..file_name.len() - DLL_SUFFIX.len()];
+                    if Bots::is_valid_name(name) {
+                        names.push(name.to_owned());
+                    }
+                },
+                None => {},
+            }
+        }
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bots, DEFAULT_MAX_BODY_SIZE, DEFAULT_READ_TIMEOUT, DEFAULT_DEDUP_WINDOW, DEFAULT_MAX_CONCURRENCY, toml};
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn it_works() {
+        let bots = Bots::from_config(&toml::from_str(r#"[bots]
+enabled = ["NoFlyBot", "BlasphemyBot"]
+max_body_size = 4096
+execution_timeout = 5
+
+[bots.NoFlyBot]
+max_body_size = 1024
+execution_timeout = 60
+dedup_window = 0
+max_concurrency = 1
+"#).unwrap(), Path::new("bots")).unwrap();
+        assert_eq!(bots.get("NoFlyBot").map(|s| s.get_dedup_window()), Some(0));
+        assert_eq!(bots.get("BlasphemyBot").map(|s| s.get_dedup_window()), Some(DEFAULT_DEDUP_WINDOW as usize));
+        assert_eq!(bots.get("NoFlyBot").map(|s| s.get_max_body_size()), Some(1024));
+        assert_eq!(bots.get("NoFlyBot").map(|s| s.get_execution_timeout()), Some(Duration::from_secs(60)));
+        assert_eq!(bots.get("BlasphemyBot").map(|s| s.get_execution_timeout()), Some(Duration::from_secs(5)));
+        assert_eq!(bots.get("BlasphemyBot").map(|s| s.get_read_timeout()), Some(Duration::from_secs(DEFAULT_READ_TIMEOUT)));
+        assert_eq!(bots.get("BlasphemyBot").map(|s| s.get_max_body_size()), Some(4096));
+        assert!(bots.get("..").is_none());
+        assert!(bots.get("OtherBot").is_none());
+        assert_eq!(bots.get("BlasphemyBot").map(|s| s.get_max_concurrency()), Some(DEFAULT_MAX_CONCURRENCY as usize));
+
+        let slot = bots.acquire("NoFlyBot");
+        assert!(slot.is_some());
+        assert!(bots.acquire("NoFlyBot").is_none());
+        drop(slot);
+        assert!(bots.acquire("NoFlyBot").is_some());
+        assert!(bots.acquire("OtherBot").is_none());
+
+        assert!(Bots::from_config(&toml::from_str("[bots]\nenabled = [\"../etc/passwd\"]").unwrap(), Path::new("bots")).is_err());
+
+        //the repo bots folder only holds .keep until some bot is linked in
+        let scanned = Bots::from_config(&toml::from_str("").unwrap(), Path::new("bots")).unwrap();
+        assert!(scanned.get("keep").is_none());
+        assert!(scanned.bots.values().all(|s| s.get_max_body_size() == DEFAULT_MAX_BODY_SIZE));
+    }
+}
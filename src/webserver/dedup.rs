@@ -0,0 +1,89 @@
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UpdateState {
+    /// bot is still handling the update
+    InFlight,
+    /// bot handled the update successfully
+    Done,
+}
+
+/// Remembers the most recent update_ids a bot received, so that updates
+/// delivered again by Telegram aren't handled twice.
+/// Failed updates are forgotten, so that Telegram's retry is handled.
+pub struct UpdateWindow {
+    order: VecDeque<u64>,
+    states: HashMap<u64, UpdateState>,
+}
+
+impl UpdateWindow {
+    pub fn new() -> UpdateWindow {
+        UpdateWindow {
+            order: VecDeque::new(),
+            states: HashMap::new(),
+        }
+    }
+
+    /// registers an update as in flight, returns false if it's a duplicate
+    /// size is the number of update_ids remembered, 0 disables the check
+    pub fn begin(&mut self, update_id: u64, size: usize) -> bool {
+        if size == 0 {
+            return true;
+        }
+        if self.states.contains_key(&update_id) {
+            return false;
+        }
+
+        self.states.insert(update_id, UpdateState::InFlight);
+        self.order.push_back(update_id);
+        while self.order.len() > size {
+            match self.order.pop_front() {
+                Some(old) => { self.states.remove(&old); },
+                None => break,
+            }
+        }
+        true
+    }
+
+    /// marks an update as handled, failed updates are forgotten
+    pub fn finish(&mut self, update_id: u64, success: bool) {
+        if success {
+            match self.states.get_mut(&update_id) {
+                Some(state) => { *state = UpdateState::Done; },
+                None => {},
+            }
+        }
+        else if self.states.remove(&update_id).is_some() {
+            self.order.retain(|id| *id != update_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UpdateWindow;
+
+    #[test]
+    fn it_works() {
+        let mut window = UpdateWindow::new();
+
+        assert!(window.begin(1, 2));
+        //in flight updates are duplicates too
+        assert!(!window.begin(1, 2));
+        window.finish(1, true);
+        assert!(!window.begin(1, 2));
+
+        //failed updates can be retried
+        assert!(window.begin(2, 2));
+        window.finish(2, false);
+        assert!(window.begin(2, 2));
+        window.finish(2, true);
+
+        //oldest updates are forgotten
+        assert!(window.begin(3, 2));
+        assert!(window.begin(1, 2));
+
+        //size 0 disables the check
+        assert!(window.begin(3, 0));
+    }
+}
@@ -0,0 +1,167 @@
+extern crate serde_json;
+extern crate toml;
+extern crate client_lib;
+
+use std::sync::Arc;
+
+use self::serde_json::value::Value as JsonValue;
+
+use self::toml::Value as TomlValue;
+
+use self::client_lib::entities::Request;
+
+/// Code running around every bot, like client_lib::middleware::Middleware does around a single one
+pub trait Middleware: Send + Sync {
+    /// inspects a Request before the bot runs, returning a result skips the bot
+    fn before(&self, _bot: &str, _request: &Request) -> Option<Result<JsonValue, String>> {
+        None
+    }
+
+    /// decorates the bot result, or handles its error
+    fn after(&self, _bot: &str, _request: &Request, result: Result<JsonValue, String>) -> Result<JsonValue, String> {
+        result
+    }
+}
+
+/// Server-level middlewares, shared by every PluginRunner
+/// they run before bots in the order they were added, and after them in reverse order
+#[derive(Clone)]
+pub struct Pipeline {
+    middlewares: Vec<Arc<dyn Middleware>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline {
+            middlewares: Vec::new(),
+        }
+    }
+
+    /// builds the middlewares enabled in the [middleware] config section
+    pub fn from_config(config: &TomlValue) -> Result<Pipeline, String> {
+        let mut pipeline = Pipeline::new();
+        let section = match config.get("middleware") {
+            Some(s) => s,
+            None => { return Ok(pipeline); },
+        };
+
+        match section.get("log") {
+            Some(value) => if value.as_bool().ok_or(String::from("Error interpreting middleware.log value"))? {
+                pipeline.add(Arc::new(Logger));
+            },
+            None => {},
+        }
+
+        match section.get("blocked_users") {
+            Some(value) => {
+                let users = value.as_array().ok_or(String::from("Error interpreting middleware.blocked_users value"))?
+                    .iter()
+                    .map(|user| user.as_integer().ok_or(format!("Invalid blocked user {}", user)))
+                    .collect::<Result<Vec<i64>, String>>()?;
+                if !users.is_empty() {
+                    pipeline.add(Arc::new(Blocklist::new(users)));
+                }
+            },
+            None => {},
+        }
+
+        Ok(pipeline)
+    }
+
+    /// appends a middleware
+    pub fn add(&mut self, middleware: Arc<dyn Middleware>) {
+        self.middlewares.push(middleware);
+    }
+
+    /// runs the Request through every middleware, calling next in the middle
+    /// a short-circuited result only goes through the middlewares that already ran
+    pub fn run<F>(&self, bot: &str, request: &Request, next: F) -> Result<JsonValue, String>
+        where F: FnOnce() -> Result<JsonValue, String>
+    {
+        let mut ran = 0;
+        let mut res = None;
+        for middleware in &self.middlewares {
+            res = middleware.before(bot, request);
+            if res.is_some() {
+                break;
+            }
+            ran += 1;
+        }
+        let mut res = match res {
+            Some(r) => r,
+            None => next(),
+        };
+        for middleware in self.middlewares[..ran].iter().rev() {
+            res = middleware.after(bot, request, res);
+        }
+        res
+    }
+}
+
+/// prints every update outcome
+pub struct Logger;
+
+impl Middleware for Logger {
+    fn after(&self, bot: &str, request: &Request, result: Result<JsonValue, String>) -> Result<JsonValue, String> {
+        match result {
+            Ok(_) => println!("Bot {} handled update_id {:?}", bot, request.get_update_id()),
+            Err(ref e) => println!("Bot {} failed update_id {:?}: {}", bot, request.get_update_id(), e),
+        }
+        result
+    }
+}
+
+/// acknowledges updates from the given users without running bots
+pub struct Blocklist {
+    users: Vec<i64>,
+}
+
+impl Blocklist {
+    pub fn new(users: Vec<i64>) -> Blocklist {
+        Blocklist {
+            users: users,
+        }
+    }
+}
+
+impl Middleware for Blocklist {
+    fn before(&self, _bot: &str, request: &Request) -> Option<Result<JsonValue, String>> {
+        match request.get_from() {
+            Some(user) if self.users.contains(&user.get_id()) => Some(Ok(JsonValue::Null)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Middleware, Pipeline, serde_json, toml};
+    use super::client_lib::entities::Request;
+    use super::serde_json::value::Value as JsonValue;
+    use std::sync::Arc;
+
+    struct Rename;
+
+    impl Middleware for Rename {
+        fn after(&self, bot: &str, _request: &Request, result: Result<JsonValue, String>) -> Result<JsonValue, String> {
+            result.map_err(|e| format!("{}: {}", bot, e))
+        }
+    }
+
+    fn request(user_id: i64) -> Request {
+        serde_json::from_str(&format!(r#"{{"update_id":1,"message":{{"message_id":1,"date":0,"chat":{{"id":1,"type":"private"}},"from":{{"is_bot":false,"id":{},"first_name":"Test"}},"text":"hello"}}}}"#, user_id)).unwrap()
+    }
+
+    #[test]
+    fn it_works() {
+        let config: toml::Value = toml::from_str("[middleware]\nlog = true\nblocked_users = [13]").unwrap();
+        let mut pipeline = Pipeline::from_config(&config).unwrap();
+        pipeline.add(Arc::new(Rename));
+
+        assert_eq!(pipeline.run("TestBot", &request(1), || Err(String::from("failed"))), Err(String::from("TestBot: failed")));
+        assert_eq!(pipeline.run("TestBot", &request(13), || Ok(JsonValue::from(true))), Ok(JsonValue::Null));
+        assert_eq!(pipeline.run("TestBot", &request(1), || Ok(JsonValue::from(true))), Ok(JsonValue::from(true)));
+
+        assert!(Pipeline::from_config(&toml::from_str("[middleware]\nblocked_users = [\"pippo\"]").unwrap()).is_err());
+    }
+}
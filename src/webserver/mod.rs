@@ -1,54 +1,261 @@
 extern crate hyper;
 extern crate futures;
+extern crate tokio_timer;
 extern crate regex;
 extern crate serde_json;
+extern crate toml;
 extern crate client_lib;
 
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use self::toml::Value as TomlValue;
 
 use self::futures::future::Future;
 use self::futures::Stream;
+use self::futures::sync::oneshot;
 
 use self::hyper::{Method, StatusCode};
+use self::hyper::header::{ContentLength, ContentType};
+use self::hyper::Body;
 use self::hyper::server::{Request, Response, Service};
 
 use self::regex::Regex;
 
+use self::tokio_timer::{Timer, TimeoutError};
+
 use self::client_lib::entities::Request as TelegramRequest;
 
+mod bots;
+mod dedup;
+mod middleware;
 mod registry;
+mod security;
+mod session;
+mod shutdown;
 
 //singleton
 lazy_static! {
     static ref REGISTRY: Arc<Mutex<registry::PluginRegistry>> = Arc::new(Mutex::new(registry::PluginRegistry::new()));
 }
 
-pub struct WebServer;
+/// possible failures while serving a webhook
+enum CallError {
+    /// body bigger than allowed
+    TooLarge,
+    /// a timer elapsed, see ReadTimeout and ExecutionTimeout
+    Timeout,
+    /// body not received in time
+    ReadTimeout,
+    /// bot didn't complete in time
+    ExecutionTimeout,
+    /// error reading body
+    Hyper(hyper::Error),
+    /// server is shutting down
+    Unavailable,
+    /// URL secret doesn't match bot's one
+    Unauthorized,
+    /// any other error, reported in response body
+    Internal(String),
+}
+
+impl<T> From<TimeoutError<T>> for CallError {
+    fn from(_: TimeoutError<T>) -> CallError {
+        CallError::Timeout
+    }
+}
+
+/// default seconds allowed to running bots to complete on shutdown
+pub const DEFAULT_DRAIN_TIMEOUT: u64 = 30;
+
+#[derive(Clone)]
+pub struct WebServer {
+    security: Arc<security::Security>,
+    bots: Arc<bots::Bots>,
+    timer: Timer,
+    shutdown: Arc<shutdown::Shutdown>,
+    drain_timeout: Duration,
+}
 
 impl WebServer {
+    /// reads webserver settings from config
+    pub fn new(config: &TomlValue) -> Result<WebServer, String> {
+        let section = config.get("shutdown");
+        let drain_timeout = match section.and_then(|s| s.get("drain_timeout")) {
+            Some(value) => match value.as_integer() {
+                Some(secs) if secs >= 0 => Duration::from_secs(secs as u64),
+                _ => { return Err(String::from("Error interpreting shutdown.drain_timeout value")); },
+            },
+            None => Duration::from_secs(DEFAULT_DRAIN_TIMEOUT),
+        };
+        match section.and_then(|s| s.get("snapshot_dir")) {
+            Some(value) => {
+                let dir = PathBuf::from(value.as_str().ok_or(String::from("Error interpreting shutdown.snapshot_dir value"))?);
+                REGISTRY.lock().map_err(|e| format!("Unable to lock plugin registry: {}", e))?.set_snapshot_dir(&dir);
+            },
+            None => {},
+        }
+        let middlewares = middleware::Pipeline::from_config(config)?;
+        REGISTRY.lock().map_err(|e| format!("Unable to lock plugin registry: {}", e))?.set_middlewares(middlewares);
+
+        Ok(WebServer {
+            security: Arc::new(security::Security::from_config(config)?),
+            bots: Arc::new(bots::Bots::from_config(config, Path::new("bots"))?),
+            timer: Timer::default(),
+            shutdown: Arc::new(shutdown::Shutdown::new()),
+            drain_timeout: drain_timeout,
+        })
+    }
+
+    /// refuses new webhooks, Telegram will deliver them again after restart
+    pub fn stop_accepting(&self) {
+        self.shutdown.stop();
+    }
+
+    /// Stops accepting webhooks, waits for running bots, then saves every session
+    pub fn shutdown(&self) {
+        let running = self.shutdown.drain(self.drain_timeout);
+        if running > 0 {
+            println!("Shutting down with {} bots still running", running);
+        }
+        match REGISTRY.lock() {
+            Ok(registry) => match registry.snapshot() {
+                Ok(_) => {},
+                Err(e) => println!("{}", e),
+            },
+            Err(e) => println!("Unable to lock plugin registry: {}", e),
+        }
+    }
+
+    /// Reads the body, within size and time limits, then dispatchs it
+    fn read_body(&self, body: Body, bot: String, secret: String, settings: &bots::BotSettings) -> Box<Future<Item=Response, Error=hyper::Error>> {
+        //refuse updates while the bot already uses all its threads, Telegram will retry them
+        let slot = match self.bots.acquire(&bot) {
+            Some(s) => s,
+            None => {
+                println!("Bot {} is running too many updates, refusing a new one", bot);
+                return Box::new(futures::future::ok(
+                    Response::new().with_status(StatusCode::ServiceUnavailable)
+                ));
+            },
+        };
+        let max_body_size = settings.get_max_body_size();
+        let execution_timeout = settings.get_execution_timeout();
+        let dedup_window = settings.get_dedup_window();
+        let timer = self.timer.clone();
+        let shutdown = self.shutdown.clone();
+
+        //concat every request's body chunk, until the limit
+        let read = body.map_err(CallError::Hyper)
+            .fold(Vec::new(), move |mut chunks, chunk| {
+                if (chunks.len() + chunk.len()) as u64 > max_body_size {
+                    Err(CallError::TooLarge)
+                }
+                else {
+                    chunks.extend_from_slice(&chunk);
+                    Ok(chunks)
+                }
+            });
+
+        Box::new(self.timer.timeout(read, settings.get_read_timeout())
+            .map_err(|e| match e {
+                CallError::Timeout => CallError::ReadTimeout,
+                other => other,
+            })
+            .and_then(move |chunks| WebServer::map_body(timer, shutdown, slot, execution_timeout, dedup_window, bot, secret, chunks))
+            .then(|res| Ok::<Response, hyper::Error>(match res {
+                //webhook replies are executed by Telegram itself, and need the right content type
+                Ok((true, out)) => Response::new().with_status(StatusCode::Ok).with_header(ContentType::json()).with_body(out),
+                Ok((false, out)) => Response::new().with_status(StatusCode::Ok).with_body(out),
+                Err(CallError::TooLarge) => Response::new().with_status(StatusCode::PayloadTooLarge),
+                Err(CallError::ReadTimeout) => Response::new().with_status(StatusCode::RequestTimeout),
+                Err(CallError::Timeout) | Err(CallError::ExecutionTimeout) => Response::new().with_status(StatusCode::GatewayTimeout),
+                Err(CallError::Unavailable) => Response::new().with_status(StatusCode::ServiceUnavailable),
+                Err(CallError::Unauthorized) => Response::new().with_status(StatusCode::Unauthorized),
+                Err(CallError::Hyper(e)) => Response::new().with_status(StatusCode::BadRequest).with_body(format!("Error reading request body: {}", e)),
+                Err(CallError::Internal(e)) => Response::new().with_status(StatusCode::InternalServerError).with_body(e),
+            })))
+    }
+
     /// Dispatchs the call to the right bot, if possible
-    fn map_body(bot: String, secret: String, chunks: Vec<u8>) -> Response {
+    fn map_body(timer: Timer, shutdown: Arc<shutdown::Shutdown>, slot: bots::Slot, execution_timeout: Duration, dedup_window: usize, bot: String, secret: String, chunks: Vec<u8>) -> Box<Future<Item=(bool, String), Error=CallError>> {
         //acquire a reference to bot registry
         let reg = REGISTRY.clone();
 
         //convert chunks to String
-        match (String::from_utf8(chunks).map_err(|e| format!("Unable to convert request body to string: {}", e)))
+        let prepared = (String::from_utf8(chunks).map_err(|e| format!("Unable to convert request body to string: {}", e)))
             //convert request to struct Request
             .and_then(|body| serde_json::from_str::<TelegramRequest>(&body).map_err(|e| format!("Syntax error on json request: {}", e)))
-            .and_then(|ref request|
+            .and_then(|request|
                 //lock bot registry
                 (reg.lock().map_err(|e| format!("Unable to lock plugin registry: {}", e)))
-                //load selected bot
-                .and_then(|mut plugin_registry| plugin_registry.load_plugin(&bot)
-                    //run bot
-                    .and_then(|plugin| plugin.run(secret, request))
-                    //convert output to string
-                    .and_then(|res| Ok(res.to_string()))
-                )
-            ) {
-            Ok(out) => Response::new().with_status(StatusCode::Ok).with_body(out),
-            Err(e) => Response::new().with_status(StatusCode::InternalServerError).with_body(e),
+                //load selected bot, registry lock is released before running it
+                .and_then(|mut plugin_registry| {
+                    let middlewares = plugin_registry.get_middlewares().clone();
+                    plugin_registry.load_plugin(&bot).and_then(|plugin| plugin.get_runner(&middlewares))
+                })
+                .map(|runner| (runner, request))
+            );
+
+        let (runner, request) = match prepared {
+            Ok(v) => v,
+            Err(e) => { return Box::new(futures::future::err(CallError::Internal(e))); },
+        };
+        let update_id = request.get_update_id().clone();
+
+        //refuse to start new runs once shutdown began, sessions may be already saved
+        let run = match shutdown::Shutdown::start_run(&shutdown) {
+            Some(r) => r,
+            None => { return Box::new(futures::future::err(CallError::Unavailable)); },
+        };
+
+        //forged requests must not reserve update_ids, or Telegram's genuine delivery would be skipped
+        if !runner.authenticate(&secret) {
+            return Box::new(futures::future::err(CallError::Unauthorized));
+        }
+
+        //acknowledge updates Telegram delivered again, without running the bot
+        match update_id {
+            Some(id) => match runner.begin_update(id, dedup_window) {
+                Ok(true) => {},
+                Ok(false) => {
+                    println!("Bot {} already received update_id {}, skipping", bot, id);
+                    return Box::new(futures::future::ok((false, String::new())));
+                },
+                Err(e) => { return Box::new(futures::future::err(CallError::Internal(e))); },
+            },
+            None => {},
         }
+
+        //run bot on its own thread, a hung bot must not block the server,
+        //its slot is released only when the thread ends, bounding threads of bots that never return
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let res = runner.run(&secret, &request);
+            //failed updates are forgotten, so that Telegram's retry is handled
+            match update_id {
+                Some(id) => runner.finish_update(id, res.is_ok()),
+                None => {},
+            }
+            let _ = tx.send(res);
+            //run ends here, even if the request already timed out
+            drop(run);
+            drop(slot);
+        });
+
+        Box::new(timer.timeout(rx.map_err(|_| CallError::Internal(String::from("Bot execution aborted"))), execution_timeout)
+            .map_err(move |e| match e {
+                CallError::Timeout => {
+                    //Telegram will deliver this update again
+                    println!("Bot {} timed out handling update_id {:?}", bot, update_id);
+                    CallError::ExecutionTimeout
+                },
+                other => other,
+            })
+            //convert output to string, flagging webhook replies
+            .and_then(|res| res.map(|value| (client_lib::is_webhook_reply(&value), value.to_string())).map_err(CallError::Internal)))
     }
 }
 
@@ -65,6 +272,18 @@ impl Service for WebServer {
     fn call(&self, req: Request) -> Self::Future {
         match req.method() {
             &Method::Post => {
+                if !self.shutdown.is_accepting() {
+                    return Box::new(futures::future::ok(
+                        Response::new().with_status(StatusCode::ServiceUnavailable)
+                    ));
+                }
+                //reject unknown sources before doing anything else
+                if !self.security.check_ip(req.remote_addr()) {
+                    return Box::new(futures::future::ok(
+                        Response::new().with_status(StatusCode::Forbidden)
+                    ));
+                }
+
                 lazy_static! {
                     static ref RE: Regex = Regex::new(r"^/Telegram/([^/]+)/([^/]+)").expect("Unable to compile regexp");
                 }
@@ -75,8 +294,31 @@ impl Service for WebServer {
                     Some(matches) => {
                         let bot = String::from(&matches[1]);
                         let secret = String::from(&matches[2]);
-                        //concat every request's body chunk
-                        Box::new(req.body().concat2().map(move |chunks| { WebServer::map_body(bot, secret, chunks.to_vec()) }))
+                        //unknown bots never reach the dynamic loader
+                        let settings = match self.bots.get(&bot) {
+                            Some(s) => s,
+                            None => {
+                                return Box::new(futures::future::ok(
+                                    Response::new().with_status(StatusCode::NotFound)
+                                ));
+                            },
+                        };
+                        //verify Telegram's secret token header before reading body or loading the plugin
+                        if !self.security.check_token(&bot, &req) {
+                            return Box::new(futures::future::ok(
+                                Response::new().with_status(StatusCode::Unauthorized)
+                            ));
+                        }
+                        //refuse declared oversized bodies before reading them
+                        match req.headers().get::<ContentLength>() {
+                            Some(&ContentLength(length)) if length > settings.get_max_body_size() => {
+                                return Box::new(futures::future::ok(
+                                    Response::new().with_status(StatusCode::PayloadTooLarge)
+                                ));
+                            },
+                            _ => {},
+                        }
+                        self.read_body(req.body(), bot, secret, settings)
                     },
                     None => Box::new(futures::future::ok(
                         Response::new().with_status(StatusCode::NotFound)
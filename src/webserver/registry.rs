@@ -4,12 +4,12 @@ extern crate serde_json;
 extern crate toml;
 extern crate client_lib;
 
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::sync::mpsc::{channel, Receiver, TryRecvError};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
-use std::fs::File;
+use std::time::{Duration, Instant};
+use std::fs::{self, File};
 use std::io::Read;
 use std::mem::transmute;
 
@@ -23,24 +23,80 @@ use self::toml::Value as TomlValue;
 
 use self::client_lib::entities::Request;
 
+use super::dedup::UpdateWindow;
+use super::middleware::Pipeline;
+use super::session::{JsonFileStore, Session, SessionStore};
+
+/// minimum seconds between removals of expired session entries, performed when the bot is loaded for an update
+const GC_INTERVAL: u64 = 60;
+
 /// 
 pub struct Plugin {
     name: String,
     config: Arc<RwLock<TomlValue>>,
-    session: Arc<RwLock<HashMap<String, JsonValue>>>,
+    session: Arc<Session>,
+    updates: Arc<Mutex<UpdateWindow>>,
+    last_gc: Instant,
     plugins: Vec<(Arc<Lib>, Arc<Symbol<'static, extern "C" fn(config: *const Arc<RwLock<TomlValue>>, session: *const Arc<RwLock<HashMap<String, JsonValue>>>, secret: &str, request: *const &Request) -> *const Result<JsonValue, String>>>)>,
 }
 
 impl Plugin {
-    pub fn new(name: &str) -> Result<Plugin, String> {
+    pub fn new(name: &str, snapshot_dir: &Path) -> Result<Plugin, String> {
+        let config = Plugin::load_config(name)?;
+        //session store is read only here, changing it requires a restart
+        let session = Session::from_config(name, &config)?;
+        //in-memory sessions are restored from the snapshot taken on last shutdown
+        if !session.is_persistent() {
+            let snapshot_path = Plugin::snapshot_path(name, snapshot_dir);
+            let snapshot = JsonFileStore::new(&snapshot_path).load()?;
+            if !snapshot.is_empty() {
+                session.restore(snapshot)?;
+                println!("Restored session snapshot for {}", name);
+            }
+            //a snapshot is restored only once, after a crash it would bring back stale data
+            if snapshot_path.exists() {
+                fs::remove_file(&snapshot_path).map_err(|e| format!("Unable to remove session snapshot {:?}: {:?}", snapshot_path, e))?;
+            }
+        }
         Ok(Plugin {
             name: name.to_owned(),
-            config: Arc::new(RwLock::new(Plugin::load_config(name)?)),
-            session: Arc::new(RwLock::new(HashMap::new())),
+            config: Arc::new(RwLock::new(config)),
+            session: session,
+            updates: Arc::new(Mutex::new(UpdateWindow::new())),
+            last_gc: Instant::now(),
             plugins: Vec::new()
         })
     }
 
+    fn snapshot_path(name: &str, snapshot_dir: &Path) -> PathBuf {
+        let mut path = snapshot_dir.to_path_buf();
+        path.push(format!("{}.session.json", name));
+        path
+    }
+
+    /// persists the session: persistent stores are flushed, in-memory sessions are written to a snapshot
+    fn snapshot(&self, snapshot_dir: &Path) -> Result<(), String> {
+        if self.session.is_persistent() {
+            self.session.flush()
+        }
+        else {
+            JsonFileStore::new(&Plugin::snapshot_path(&self.name, snapshot_dir)).save(&self.session.snapshot()?)
+        }
+    }
+
+    /// removes expired session entries, at most once every GC_INTERVAL
+    fn collect_garbage(&mut self) {
+        if self.last_gc.elapsed() < Duration::from_secs(GC_INTERVAL) {
+            return;
+        }
+        self.last_gc = Instant::now();
+        match self.session.collect_garbage() {
+            Ok(0) => {},
+            Ok(removed) => println!("Removed {} expired session entries for {}", removed, self.name),
+            Err(e) => println!("Unable to clean session for {}: {}", self.name, e),
+        }
+    }
+
     fn add_plugin(&mut self, plugin: &Arc<Lib>) {
         match unsafe { plugin.lib.get(b"init_bot\0") } {
             Ok(temp) => {
@@ -72,25 +128,21 @@ impl Plugin {
         }
     }
 
-    pub fn run(&self, secret: String, request: &Request) -> Result<&JsonValue, String> {
+    /// returns an handle to run the plugin, wrapped by the given middlewares, without keeping the registry locked
+    pub fn get_runner(&self, middlewares: &Pipeline) -> Result<PluginRunner, String> {
         if self.plugins.len() == 0 {
             return Err(format!("Lib {} not loaded", self.name));
         }
 
-        let f = &self.plugins[0].1;
-        let res = f(Box::into_raw(Box::new(self.config.clone())), Box::into_raw(Box::new(self.session.clone())), &secret, Box::into_raw(Box::new(request)));
-
-        unsafe {
-            if res.is_null() {
-                Err(format!("Null pointer exception"))
-            }
-            else {
-                match *res {
-                    Ok(ref v) => Ok(v),
-                    Err(ref e) => Err(e.to_string()),
-                }
-            }
-        }
+        Ok(PluginRunner {
+            name: self.name.clone(),
+            _lib: self.plugins[0].0.clone(),
+            f: self.plugins[0].1.clone(),
+            config: self.config.clone(),
+            session: self.session.clone(),
+            updates: self.updates.clone(),
+            middlewares: middlewares.clone(),
+        })
     }
 
     fn set_config(&self, lib: &str) -> Result<(), String> {
@@ -118,9 +170,81 @@ impl Plugin {
     }
 }
 
+/// a loaded plugin, ready to run
+/// holding the lib keeps it loaded even if a reload happens meanwhile
+pub struct PluginRunner {
+    name: String,
+    _lib: Arc<Lib>,
+    f: Arc<Symbol<'static, extern "C" fn(config: *const Arc<RwLock<TomlValue>>, session: *const Arc<RwLock<HashMap<String, JsonValue>>>, secret: &str, request: *const &Request) -> *const Result<JsonValue, String>>>,
+    config: Arc<RwLock<TomlValue>>,
+    session: Arc<Session>,
+    updates: Arc<Mutex<UpdateWindow>>,
+    middlewares: Pipeline,
+}
+
+impl PluginRunner {
+    /// checks the URL secret against bot's SECRET config value, the same check Telegram::init_bot performs
+    pub fn authenticate(&self, secret: &str) -> bool {
+        match self.config.read() {
+            Ok(config) => match config.get("SECRET").and_then(|value| value.as_str()) {
+                Some(cnf_secret) => client_lib::constant_time_eq(secret.as_bytes(), cnf_secret.as_bytes()),
+                None => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// registers an update, returns false if it was already received
+    /// the window lives in Plugin, so it survives lib reloads
+    pub fn begin_update(&self, update_id: u64, window_size: usize) -> Result<bool, String> {
+        self.updates.lock()
+            .map(|mut updates| updates.begin(update_id, window_size))
+            .map_err(|e| format!("Unable to lock update window: {}", e))
+    }
+
+    /// marks an update as handled
+    pub fn finish_update(&self, update_id: u64, success: bool) {
+        match self.updates.lock() {
+            Ok(mut updates) => updates.finish(update_id, success),
+            Err(e) => println!("Unable to lock update window: {}", e),
+        }
+    }
+
+    /// runs the plugin through server-level middlewares
+    /// unauthenticated requests never reach them, so they aren't logged nor acknowledged
+    pub fn run(&self, secret: &str, request: &Request) -> Result<JsonValue, String> {
+        if !self.authenticate(secret) {
+            return Err(String::from("Secret mismatch"));
+        }
+        self.middlewares.run(&self.name, request, || self.call(secret, request))
+    }
+
+    fn call(&self, secret: &str, request: &Request) -> Result<JsonValue, String> {
+        let f = &self.f;
+        let res = f(Box::into_raw(Box::new(self.config.clone())), Box::into_raw(Box::new(self.session.get_session().clone())), secret, Box::into_raw(Box::new(request)));
+
+        //persist session changes, even if the bot failed
+        self.session.updated();
+
+        unsafe {
+            if res.is_null() {
+                Err(format!("Null pointer exception"))
+            }
+            else {
+                match *res {
+                    Ok(ref v) => Ok(v.clone()),
+                    Err(ref e) => Err(e.to_string()),
+                }
+            }
+        }
+    }
+}
+
 pub struct PluginRegistry {
     handler: DynamicReload<'static>,
     libs: HashMap<String, Plugin>,
+    snapshot_dir: PathBuf,
+    middlewares: Pipeline,
     _watcher: RecommendedWatcher,
     watch_recv: Receiver<DebouncedEvent>,
 }
@@ -139,11 +263,45 @@ impl PluginRegistry {
         PluginRegistry {
             handler: DynamicReload::new(Some(vec!["bots"]), Some("tmp"), Search::Default),
             libs: HashMap::new(),
+            snapshot_dir: PathBuf::from("tmp"),
+            middlewares: Pipeline::new(),
             _watcher: watcher,
             watch_recv: rx,
         }
     }
 
+    /// sets where in-memory sessions are saved on shutdown, defaults to tmp
+    pub fn set_snapshot_dir(&mut self, dir: &Path) {
+        self.snapshot_dir = dir.to_path_buf();
+    }
+
+    /// sets middlewares applied to every bot
+    pub fn set_middlewares(&mut self, middlewares: Pipeline) {
+        self.middlewares = middlewares;
+    }
+
+    /// returns middlewares applied to every bot
+    pub fn get_middlewares(&self) -> &Pipeline {
+        &self.middlewares
+    }
+
+    /// saves every loaded plugin's session, see Plugin::snapshot
+    pub fn snapshot(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        for plugin in self.libs.values() {
+            match plugin.snapshot(&self.snapshot_dir) {
+                Ok(_) => println!("Saved session for {}", plugin.name),
+                Err(e) => errors.push(format!("{}: {}", plugin.name, e)),
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(format!("Unable to save sessions: {}", errors.join(", ")))
+        }
+    }
+
     pub fn load_plugin(&mut self, lib: &str) -> Result<&mut Plugin, String> {
         loop {
             match self.watch_recv.try_recv() {
@@ -172,6 +330,7 @@ impl PluginRegistry {
             match self.libs.get_mut(lib) {
                 Some(mut plugin) => {
                     self.handler.update(Plugin::reload_callback, &mut plugin);
+                    plugin.collect_garbage();
                     return Ok(plugin);
                 },
                 None => { return Err(format!("Plugin disappeared: {}", lib)); },
@@ -180,7 +339,7 @@ impl PluginRegistry {
 
         match self.handler.add_library(lib, PlatformName::Yes) {
             Ok(plug) => {
-                self.libs.insert(lib.to_owned(), Plugin::new(lib)?);
+                self.libs.insert(lib.to_owned(), Plugin::new(lib, &self.snapshot_dir)?);
                 match self.libs.get_mut(lib) {
                     Some(mut plugin) => {
                         plugin.add_plugin(&plug);
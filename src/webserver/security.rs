@@ -0,0 +1,159 @@
+extern crate hyper;
+extern crate toml;
+extern crate client_lib;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use self::hyper::server::Request;
+
+use self::toml::Value as TomlValue;
+
+use self::client_lib::constant_time_eq;
+
+/// header Telegram fills with the secret_token given to setWebhook
+pub const SECRET_TOKEN_HEADER: &'static str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// an IP subnet in CIDR notation
+#[derive(Debug, PartialEq)]
+pub struct Subnet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Subnet {
+    /// parses a subnet like "149.154.160.0/20", a bare address is a single host subnet
+    pub fn parse(value: &str) -> Result<Subnet, String> {
+        let mut parts = value.splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap_or("").trim().parse().map_err(|e| format!("Invalid subnet address {}: {:?}", value, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(p) => p.trim().parse::<u8>().map_err(|e| format!("Invalid subnet prefix {}: {:?}", value, e))?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(format!("Invalid subnet prefix {}: greater than {}", value, max));
+        }
+        Ok(Subnet {
+            addr: addr,
+            prefix: prefix,
+        })
+    }
+
+    /// checks if the given address belongs to the subnet
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (&self.addr, ip) {
+            (&IpAddr::V4(ref net), &IpAddr::V4(ref ip)) => Subnet::matches(&net.octets(), &ip.octets(), self.prefix),
+            (&IpAddr::V6(ref net), &IpAddr::V6(ref ip)) => Subnet::matches(&net.octets(), &ip.octets(), self.prefix),
+            //IPv4 clients on dual stack sockets are seen as IPv4-mapped IPv6 addresses
+            (&IpAddr::V4(ref net), &IpAddr::V6(ref ip)) => if ip.segments()[..6] == [0, 0, 0, 0, 0, 0xFFFF] {
+                Subnet::matches(&net.octets(), &ip.octets()[12..], self.prefix)
+            }
+            else {
+                false
+            },
+            _ => false,
+        }
+    }
+
+    /// compares the first prefix bits of two addresses
+    fn matches(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+        let full = (prefix / 8) as usize;
+        let rest = prefix % 8;
+        if net[..full] != ip[..full] {
+            return false;
+        }
+        if rest == 0 {
+            return true;
+        }
+        let mask = 0xFFu8 << (8 - rest);
+        net[full] & mask == ip[full] & mask
+    }
+}
+
+/// Webhook requests authentication, configured in the webhook section of rustegram.toml
+pub struct Security {
+    secret_tokens: HashMap<String, String>,
+    allowed_ips: Vec<Subnet>,
+}
+
+impl Security {
+    /// reads settings from server config, everything is optional
+    pub fn from_config(config: &TomlValue) -> Result<Security, String> {
+        let mut security = Security {
+            secret_tokens: HashMap::new(),
+            allowed_ips: Vec::new(),
+        };
+
+        let webhook = match config.get("webhook") {
+            Some(value) => value,
+            None => { return Ok(security); },
+        };
+
+        match webhook.get("allowed_ips") {
+            Some(value) => for subnet in value.as_array().ok_or(String::from("Error interpreting webhook.allowed_ips value"))? {
+                security.allowed_ips.push(Subnet::parse(subnet.as_str().ok_or(String::from("Error interpreting webhook.allowed_ips value"))?)?);
+            },
+            None => {},
+        }
+
+        match webhook.get("secret_tokens") {
+            Some(value) => for (bot, token) in value.as_table().ok_or(String::from("Error interpreting webhook.secret_tokens value"))? {
+                security.secret_tokens.insert(bot.to_owned(), token.as_str().ok_or(format!("Error interpreting webhook.secret_tokens.{} value", bot))?.to_owned());
+            },
+            None => {},
+        }
+
+        Ok(security)
+    }
+
+    /// checks the request source address against allowed subnets, if any
+    pub fn check_ip(&self, addr: Option<SocketAddr>) -> bool {
+        if self.allowed_ips.is_empty() {
+            return true;
+        }
+        match addr {
+            Some(a) => self.allowed_ips.iter().any(|subnet| subnet.contains(&a.ip())),
+            None => false,
+        }
+    }
+
+    /// checks the secret token header, if a token is configured for the bot
+    pub fn check_token(&self, bot: &str, req: &Request) -> bool {
+        match self.secret_tokens.get(bot) {
+            Some(token) => match req.headers().get_raw(SECRET_TOKEN_HEADER).and_then(|raw| raw.one()) {
+                Some(header) => constant_time_eq(header, token.as_bytes()),
+                None => false,
+            },
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Security, Subnet, toml};
+
+    #[test]
+    fn it_works() {
+        let subnet = Subnet::parse("149.154.160.0/20").unwrap();
+        assert!(subnet.contains(&"149.154.167.220".parse().unwrap()));
+        assert!(!subnet.contains(&"149.154.176.1".parse().unwrap()));
+        assert!(subnet.contains(&"::ffff:149.154.160.1".parse().unwrap()));
+        assert!(Subnet::parse("91.108.4.0/33").is_err());
+        assert!(Subnet::parse("::1").unwrap().contains(&"::1".parse().unwrap()));
+
+        let security = Security::from_config(&toml::from_str(r#"[webhook]
+allowed_ips = ["149.154.160.0/20", "91.108.4.0/22"]
+
+[webhook.secret_tokens]
+NoFlyBot = "token"
+"#).unwrap()).unwrap();
+        assert!(security.check_ip(Some("91.108.6.1:443".parse().unwrap())));
+        assert!(!security.check_ip(Some("127.0.0.1:443".parse().unwrap())));
+        assert!(!security.check_ip(None));
+
+        let open = Security::from_config(&toml::from_str("").unwrap()).unwrap();
+        assert!(open.check_ip(None));
+    }
+}
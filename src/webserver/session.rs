@@ -0,0 +1,390 @@
+extern crate serde_json;
+extern crate sled;
+extern crate toml;
+extern crate client_lib;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+use self::serde_json::value::Value as JsonValue;
+
+use self::toml::Value as TomlValue;
+
+/// folder holding session files, when SESSION_PATH isn't configured
+pub const DEFAULT_SESSION_DIR: &'static str = "sessions";
+
+/// A place where bot sessions survive server restarts
+pub trait SessionStore: Send + Sync {
+    /// reads the whole session, a missing store is an empty session
+    fn load(&self) -> Result<HashMap<String, JsonValue>, String>;
+    /// replaces the stored session with the given one
+    fn save(&self, session: &HashMap<String, JsonValue>) -> Result<(), String>;
+    /// persists only changed and removed keys, session is the whole updated one for stores that can't do better
+    fn save_changes(&self, session: &HashMap<String, JsonValue>, _changed: &HashMap<String, JsonValue>, _removed: &[String]) -> Result<(), String> {
+        self.save(session)
+    }
+}
+
+/// Session kept in a single json file
+pub struct JsonFileStore {
+    path: PathBuf,
+}
+
+impl JsonFileStore {
+    /// the file is created on first save
+    pub fn new(path: &Path) -> JsonFileStore {
+        JsonFileStore {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl SessionStore for JsonFileStore {
+    fn load(&self) -> Result<HashMap<String, JsonValue>, String> {
+        match File::open(&self.path) {
+            Ok(file) => serde_json::from_reader(file).map_err(|e| format!("Syntax error on session file {:?}: {:?}", self.path, e)),
+            //missing file simply means empty session
+            Err(_) => Ok(HashMap::new()),
+        }
+    }
+
+    fn save(&self, session: &HashMap<String, JsonValue>) -> Result<(), String> {
+        match self.path.parent() {
+            Some(dir) => fs::create_dir_all(dir).map_err(|e| format!("Unable to create session dir {:?}: {:?}", dir, e))?,
+            None => {},
+        }
+
+        //write a temp file and rename it, so that a crash never leaves an half written session
+        let temp = self.path.with_extension("tmp");
+        {
+            let mut file = File::create(&temp).map_err(|e| format!("Unable to create session file {:?}: {:?}", temp, e))?;
+            serde_json::to_writer(&mut file, session).map_err(|e| format!("Unable to write session file {:?}: {:?}", temp, e))?;
+            file.flush().map_err(|e| format!("Unable to write session file {:?}: {:?}", temp, e))?;
+        }
+        fs::rename(&temp, &self.path).map_err(|e| format!("Unable to replace session file {:?}: {:?}", self.path, e))
+    }
+}
+
+/// Session kept in an embedded key-value database, one entry per session key
+pub struct KvStore {
+    db: sled::Db,
+}
+
+impl KvStore {
+    /// opens, or creates, the database at the given path
+    pub fn open(path: &Path) -> Result<KvStore, String> {
+        Ok(KvStore {
+            db: sled::Db::open(path).map_err(|e| format!("Unable to open session db {:?}: {:?}", path, e))?,
+        })
+    }
+}
+
+impl SessionStore for KvStore {
+    fn load(&self) -> Result<HashMap<String, JsonValue>, String> {
+        let mut session = HashMap::new();
+        for entry in self.db.iter() {
+            let (key, value) = entry.map_err(|e| format!("Unable to read session db: {:?}", e))?;
+            let key = String::from_utf8(key.to_vec()).map_err(|e| format!("Invalid session key: {:?}", e))?;
+            let value = serde_json::from_slice(&value).map_err(|e| format!("Syntax error on session value {}: {:?}", key, e))?;
+            session.insert(key, value);
+        }
+        Ok(session)
+    }
+
+    fn save(&self, session: &HashMap<String, JsonValue>) -> Result<(), String> {
+        for entry in self.db.iter() {
+            let (key, _) = entry.map_err(|e| format!("Unable to read session db: {:?}", e))?;
+            match String::from_utf8(key.to_vec()) {
+                Ok(ref k) if session.contains_key(k) => {},
+                _ => { self.db.remove(key).map_err(|e| format!("Unable to write session db: {:?}", e))?; },
+            }
+        }
+        for (key, value) in session {
+            let bytes = serde_json::to_vec(value).map_err(|e| format!("Unable to serialize session value {}: {:?}", key, e))?;
+            self.db.insert(key.as_bytes(), bytes).map_err(|e| format!("Unable to write session db: {:?}", e))?;
+        }
+        self.db.flush().map_err(|e| format!("Unable to flush session db: {:?}", e))?;
+        Ok(())
+    }
+    fn save_changes(&self, _session: &HashMap<String, JsonValue>, changed: &HashMap<String, JsonValue>, removed: &[String]) -> Result<(), String> {
+        for key in removed {
+            self.db.remove(key.as_bytes()).map_err(|e| format!("Unable to write session db: {:?}", e))?;
+        }
+        for (key, value) in changed {
+            let bytes = serde_json::to_vec(value).map_err(|e| format!("Unable to serialize session value {}: {:?}", key, e))?;
+            self.db.insert(key.as_bytes(), bytes).map_err(|e| format!("Unable to write session db: {:?}", e))?;
+        }
+        self.db.flush().map_err(|e| format!("Unable to flush session db: {:?}", e))?;
+        Ok(())
+    }
+}
+
+/// when session changes are persisted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlushMode {
+    /// after every update handled by the bot
+    WriteThrough,
+    /// every given interval, by a background thread
+    Periodic(Duration),
+}
+
+/// #Session
+/// Bot session shared with the plugin, optionally backed by a SessionStore.
+/// It's configured in bot's toml file:
+/// SESSION_STORE can be "memory" (default, lost on restart), "json" or "kv",
+/// SESSION_PATH overrides the default sessions/<bot>.json or sessions/<bot>.db path,
+/// SESSION_FLUSH is the flush interval in seconds, 0 (default) means write-through.
+pub struct Session {
+    name: String,
+    session: Arc<RwLock<HashMap<String, JsonValue>>>,
+    store: Option<Box<dyn SessionStore>>,
+    mode: FlushMode,
+    //last persisted copy, to write only changed keys
+    saved: Mutex<HashMap<String, JsonValue>>,
+}
+
+impl Session {
+    /// builds bot session from its config, loading the stored one
+    pub fn from_config(name: &str, config: &TomlValue) -> Result<Arc<Session>, String> {
+        let kind = match config.get("SESSION_STORE") {
+            Some(value) => value.as_str().ok_or(String::from("Error interpreting SESSION_STORE value"))?,
+            None => "memory",
+        };
+        let path = match config.get("SESSION_PATH") {
+            Some(value) => Some(PathBuf::from(value.as_str().ok_or(String::from("Error interpreting SESSION_PATH value"))?)),
+            None => None,
+        };
+        let mode = match config.get("SESSION_FLUSH") {
+            Some(value) => match value.as_integer() {
+                Some(0) => FlushMode::WriteThrough,
+                Some(secs) if secs > 0 => FlushMode::Periodic(Duration::from_secs(secs as u64)),
+                _ => { return Err(String::from("Error interpreting SESSION_FLUSH value")); },
+            },
+            None => FlushMode::WriteThrough,
+        };
+
+        let store: Option<Box<dyn SessionStore>> = match kind {
+            "memory" => None,
+            "json" => Some(Box::new(JsonFileStore::new(&path.unwrap_or(Session::default_path(name, "json"))))),
+            "kv" => Some(Box::new(KvStore::open(&path.unwrap_or(Session::default_path(name, "db")))?)),
+            other => { return Err(format!("Unknown SESSION_STORE {}", other)); },
+        };
+
+        Session::new(name, store, mode)
+    }
+
+    /// builds a session over the given store, a periodic flush spawns its own thread
+    pub fn new(name: &str, store: Option<Box<dyn SessionStore>>, mode: FlushMode) -> Result<Arc<Session>, String> {
+        let data = match store {
+            Some(ref s) => s.load()?,
+            None => HashMap::new(),
+        };
+        let persistent = store.is_some();
+
+        let session = Arc::new(Session {
+            name: name.to_owned(),
+            session: Arc::new(RwLock::new(data.clone())),
+            store: store,
+            mode: mode,
+            saved: Mutex::new(data),
+        });
+
+        match mode {
+            //the thread doesn't keep the session alive, it ends once the session is dropped, e.g. on plugin reload
+            FlushMode::Periodic(interval) if persistent => {
+                let weak: Weak<Session> = Arc::downgrade(&session);
+                thread::spawn(move || loop {
+                    thread::sleep(interval);
+                    match weak.upgrade() {
+                        Some(s) => match s.flush() {
+                            Ok(_) => {},
+                            Err(e) => println!("Unable to flush session for {}: {}", s.name, e),
+                        },
+                        None => break,
+                    }
+                });
+            },
+            _ => {},
+        }
+
+        Ok(session)
+    }
+
+    fn default_path(name: &str, extension: &str) -> PathBuf {
+        let mut path = PathBuf::from(DEFAULT_SESSION_DIR);
+        path.push(name);
+        path.set_extension(extension);
+        path
+    }
+
+    /// returns the session passed to the plugin
+    pub fn get_session(&self) -> &Arc<RwLock<HashMap<String, JsonValue>>> {
+        &self.session
+    }
+
+    /// checks if the session is persisted by a store
+    pub fn is_persistent(&self) -> bool {
+        self.store.is_some()
+    }
+
+    /// returns a copy of session contents
+    pub fn snapshot(&self) -> Result<HashMap<String, JsonValue>, String> {
+        self.session.read().map(|s| s.clone()).map_err(|e| format!("Error read locking session: {:?}", e))
+    }
+
+    /// replaces session contents, e.g. with a snapshot taken on shutdown
+    pub fn restore(&self, data: HashMap<String, JsonValue>) -> Result<(), String> {
+        self.session.write().map(|mut s| { *s = data; }).map_err(|e| format!("Error write locking session: {:?}", e))
+    }
+
+    /// removes expired entries of bot's scoped session (see client_lib::session)
+    pub fn collect_garbage(&self) -> Result<usize, String> {
+        self.session.write()
+            .map(|mut s| client_lib::session::collect_garbage(&mut s))
+            .map_err(|e| format!("Error write locking session: {:?}", e))
+    }
+
+    /// called after every handled update, persists the session if write-through
+    pub fn updated(&self) {
+        if self.mode == FlushMode::WriteThrough {
+            match self.flush() {
+                Ok(_) => {},
+                Err(e) => println!("Unable to flush session for {}: {}", self.name, e),
+            }
+        }
+    }
+
+    /// persists keys changed since last flush
+    pub fn flush(&self) -> Result<(), String> {
+        let store = match self.store {
+            Some(ref s) => s,
+            None => { return Ok(()); },
+        };
+
+        //holding saved lock serializes concurrent flushes
+        let mut saved = self.saved.lock().map_err(|e| format!("Unable to lock saved session: {:?}", e))?;
+        let (changed, removed) = {
+            let current = self.session.read().map_err(|e| format!("Error read locking session: {:?}", e))?;
+            let changed: HashMap<String, JsonValue> = current.iter()
+                .filter(|&(key, value)| saved.get(key) != Some(value))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            let removed: Vec<String> = saved.keys()
+                .filter(|key| !current.contains_key(*key))
+                .cloned()
+                .collect();
+            (changed, removed)
+        };
+        if changed.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+
+        //saved becomes the updated session, without keeping the plugin's one locked while writing
+        let mut previous = Vec::new();
+        for (key, value) in &changed {
+            previous.push((key.clone(), saved.insert(key.clone(), value.clone())));
+        }
+        for key in &removed {
+            previous.push((key.clone(), saved.remove(key)));
+        }
+        match store.save_changes(&saved, &changed, &removed) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                //forget the failed changes, so that next flush writes them again
+                for (key, value) in previous.into_iter().rev() {
+                    match value {
+                        Some(v) => { saved.insert(key, v); },
+                        None => { saved.remove(&key); },
+                    }
+                }
+                Err(e)
+            },
+        }
+    }
+}
+
+impl Drop for Session {
+    /// pending changes of periodic sessions aren't lost when the session is dropped
+    fn drop(&mut self) {
+        match self.flush() {
+            Ok(_) => {},
+            Err(e) => println!("Unable to flush session for {}: {}", self.name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlushMode, JsonFileStore, KvStore, Session, SessionStore, serde_json, toml};
+    use super::serde_json::value::Value as JsonValue;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn open(path: &Path) -> Box<dyn SessionStore> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("db") => Box::new(KvStore::open(path).unwrap()),
+            _ => Box::new(JsonFileStore::new(path)),
+        }
+    }
+
+    #[test]
+    fn it_works() {
+        let dir = env::temp_dir().join(format!("rustegram_session_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let value: JsonValue = serde_json::from_str(r#"{"value": 1}"#).unwrap();
+
+        for path in &[dir.join("bot.json"), dir.join("bot.db")] {
+            let session = Session::new("bot", Some(open(path)), FlushMode::WriteThrough).unwrap();
+            session.get_session().write().unwrap().insert(String::from("key"), value.clone());
+            session.get_session().write().unwrap().insert(String::from("other"), JsonValue::from(2));
+            session.updated();
+            session.get_session().write().unwrap().remove("other");
+            session.flush().unwrap();
+            drop(session);
+
+            //a new session, like after a restart, finds the stored values
+            let restored = Session::new("bot", Some(open(path)), FlushMode::WriteThrough).unwrap();
+            let data = restored.get_session().read().unwrap();
+            assert_eq!(data.get("key"), Some(&value));
+            assert!(data.get("other").is_none());
+        }
+
+        //periodic flush doesn't write on updates
+        let path = dir.join("periodic.json");
+        let session = Session::new("bot", Some(open(&path)), FlushMode::Periodic(Duration::from_secs(3600))).unwrap();
+        session.get_session().write().unwrap().insert(String::from("key"), JsonValue::from(1));
+        session.updated();
+        assert!(!path.exists());
+        session.flush().unwrap();
+        assert!(path.exists());
+        //the flush thread doesn't keep the session alive
+        assert_eq!(Arc::strong_count(&session), 1);
+
+        //dropped sessions write their pending changes
+        let dropped = Session::new("bot", Some(open(&dir.join("dropped.db"))), FlushMode::Periodic(Duration::from_secs(3600))).unwrap();
+        dropped.get_session().write().unwrap().insert(String::from("key"), JsonValue::from(3));
+        drop(dropped);
+        assert_eq!(open(&dir.join("dropped.db")).load().unwrap().get("key"), Some(&JsonValue::from(3)));
+
+        //memory sessions can be restored from a snapshot
+        let memory = Session::from_config("bot", &toml::from_str("").unwrap()).unwrap();
+        assert!(!memory.is_persistent());
+        memory.restore(session.snapshot().unwrap()).unwrap();
+        assert_eq!(memory.get_session().read().unwrap().get("key"), Some(&JsonValue::from(1)));
+
+        assert!(Session::from_config("bot", &toml::from_str("SESSION_STORE = \"redis\"").unwrap()).is_err());
+        assert!(Session::from_config("bot", &toml::from_str("SESSION_FLUSH = -1").unwrap()).is_err());
+        assert!(Session::from_config("bot", &toml::from_str("").unwrap()).unwrap().flush().is_ok());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+struct State {
+    accepting: bool,
+    running: usize,
+}
+
+/// Tracks bot runs in flight, so that a shutdown can wait for them
+pub struct Shutdown {
+    state: Mutex<State>,
+    idle: Condvar,
+}
+
+/// a bot run in flight, ends when dropped
+pub struct RunGuard {
+    shutdown: Arc<Shutdown>,
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        match self.shutdown.state.lock() {
+            Ok(mut state) => {
+                state.running -= 1;
+                self.shutdown.idle.notify_all();
+            },
+            Err(e) => println!("Unable to lock running bots: {:?}", e),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown {
+            state: Mutex::new(State {
+                accepting: true,
+                running: 0,
+            }),
+            idle: Condvar::new(),
+        }
+    }
+
+    /// checks if new requests are still accepted
+    pub fn is_accepting(&self) -> bool {
+        self.state.lock().map(|state| state.accepting).unwrap_or(false)
+    }
+
+    /// registers a new bot run, None if shutdown already began
+    pub fn start_run(shutdown: &Arc<Shutdown>) -> Option<RunGuard> {
+        match shutdown.state.lock() {
+            Ok(mut state) => if state.accepting {
+                state.running += 1;
+                Some(RunGuard {
+                    shutdown: shutdown.clone(),
+                })
+            }
+            else {
+                None
+            },
+            Err(_) => None,
+        }
+    }
+
+    /// stops accepting new runs
+    pub fn stop(&self) {
+        match self.state.lock() {
+            Ok(mut state) => { state.accepting = false; },
+            Err(e) => println!("Unable to lock running bots: {:?}", e),
+        }
+    }
+
+    /// stops accepting new runs, then waits for the running ones up to timeout
+    /// returns the number of runs still in flight
+    pub fn drain(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut state = match self.state.lock() {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Unable to lock running bots: {:?}", e);
+                return 0;
+            },
+        };
+        state.accepting = false;
+
+        while state.running > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = match self.idle.wait_timeout(state, deadline - now) {
+                Ok((s, _)) => s,
+                Err(e) => {
+                    println!("Unable to wait running bots: {:?}", e);
+                    return 0;
+                },
+            };
+        }
+        state.running
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shutdown;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn it_works() {
+        let shutdown = Arc::new(Shutdown::new());
+        assert!(shutdown.is_accepting());
+
+        let quick = Shutdown::start_run(&shutdown).unwrap();
+        let slow = Shutdown::start_run(&shutdown).unwrap();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(quick);
+        });
+
+        //the slow run is still in flight when timeout elapses
+        assert_eq!(shutdown.drain(Duration::from_millis(500)), 1);
+        assert!(!shutdown.is_accepting());
+        assert!(Shutdown::start_run(&shutdown).is_none());
+
+        drop(slow);
+        assert_eq!(shutdown.drain(Duration::from_millis(500)), 0);
+    }
+}
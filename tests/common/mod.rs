@@ -0,0 +1,237 @@
+//! Webserver end-to-end test harness
+//!
+//! Builds the test plugin, then starts the real rustegram binary inside a temporary
+//! directory with its own config/, bots/ and tmp/ folders, pointing the plugin to an
+//! in-process Telegram stand-in.
+
+#![allow(dead_code)]
+
+extern crate client_lib;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, ExitStatus, Stdio};
+use std::sync::{Once, ONCE_INIT};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::Duration;
+
+pub use self::client_lib::mock::MockServer;
+
+/// name of the test plugin, as used in URLs
+pub const BOT: &'static str = "test_bot";
+/// secret configured for the test plugin
+pub const SECRET: &'static str = "prova";
+
+static BUILD: Once = ONCE_INIT;
+static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// path of the test plugin crate
+fn plugin_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("plugins").join(BOT)
+}
+
+/// platform specific file name of the test plugin
+fn plugin_file() -> String {
+    format!("{}{}{}", env::consts::DLL_PREFIX, BOT, env::consts::DLL_SUFFIX)
+}
+
+/// builds the test plugin only once per test run
+fn build_plugin() {
+    BUILD.call_once(|| {
+        let status = Command::new(env::var("CARGO").unwrap_or(String::from("cargo")))
+            .arg("build")
+            .arg("--manifest-path")
+            .arg(plugin_dir().join("Cargo.toml"))
+            .status()
+            .expect("Unable to run cargo");
+        assert!(status.success(), "Unable to build test plugin");
+    });
+}
+
+/// reads a recorded update from tests/fixtures
+pub fn fixture(name: &str) -> Vec<u8> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join(name);
+    let mut data = Vec::new();
+    File::open(&path).and_then(|mut f| f.read_to_end(&mut data)).expect(&format!("Unable to read fixture {:?}", path));
+    data
+}
+
+/// HTTP response as seen by tests
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl TestResponse {
+    /// returns the value of the given header, case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|&&(ref k, _)| k.eq_ignore_ascii_case(name)).map(|&(_, ref v)| v.as_str())
+    }
+}
+
+/// running rustegram instance
+pub struct TestServer {
+    dir: PathBuf,
+    port: u16,
+    child: Child,
+    telegram: MockServer,
+}
+
+impl TestServer {
+    /// starts the server, server_config and bot_config are appended to the generated toml files
+    pub fn start(server_config: &str, bot_config: &str) -> TestServer {
+        build_plugin();
+
+        let telegram = MockServer::start().expect("Unable to start Telegram stand-in");
+
+        let dir = env::temp_dir().join(format!("rustegram-test-{}-{}", process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)));
+        for sub in ["config", "bots", "tmp"].iter() {
+            fs::create_dir_all(dir.join(sub)).expect("Unable to create test dir");
+        }
+        fs::copy(plugin_dir().join("target").join("debug").join(plugin_file()), dir.join("bots").join(plugin_file())).expect("Unable to copy test plugin");
+
+        //ask the OS for a free port
+        let port = TcpListener::bind("127.0.0.1:0").and_then(|l| l.local_addr()).expect("Unable to find a free port").port();
+
+        write_file(&dir.join("config").join("rustegram.toml"), &format!(r#"address = "127.0.0.1"
+port = "{}"
+{}
+[https]
+enabled = false
+"#, port, server_config));
+        write_file(&dir.join("config").join(format!("{}.toml", BOT)), &format!(r#"SECRET = "{}"
+HTTP_TOKEN = "test"
+API_URL = "{}"
+{}
+"#, SECRET, telegram.get_url(), bot_config));
+
+        let child = Command::new(env!("CARGO_BIN_EXE_rustegram"))
+            .arg("config/rustegram.toml")
+            .current_dir(&dir)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("Unable to start rustegram");
+
+        let server = TestServer {
+            dir: dir,
+            port: port,
+            child: child,
+            telegram: telegram,
+        };
+        server.wait_ready();
+        server
+    }
+
+    /// Telegram stand-in used by the plugin
+    pub fn telegram(&self) -> &MockServer {
+        &self.telegram
+    }
+
+    /// temporary working directory of the server
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// waits until the server accepts connections
+    fn wait_ready(&self) {
+        for _ in 0..100 {
+            if TcpStream::connect(("127.0.0.1", self.port)).is_ok() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("rustegram didn't start listening on port {}", self.port);
+    }
+
+    /// sends SIGTERM to the server and waits for it to exit
+    pub fn terminate(&mut self) -> ExitStatus {
+        Command::new("kill").arg("-TERM").arg(self.child.id().to_string()).status().expect("Unable to signal rustegram");
+        self.child.wait().expect("Unable to wait rustegram")
+    }
+
+    /// opens a raw connection to the server
+    pub fn connect(&self) -> TcpStream {
+        TcpStream::connect(("127.0.0.1", self.port)).expect("Unable to connect to rustegram")
+    }
+
+    /// POSTs body to the given path
+    pub fn post(&self, path: &str, body: &[u8], headers: &[(&str, &str)]) -> TestResponse {
+        self.request("POST", path, body, headers)
+    }
+
+    /// POSTs an update to the test plugin, with the right secret
+    pub fn post_update(&self, body: &[u8]) -> TestResponse {
+        self.post(&format!("/Telegram/{}/{}", BOT, SECRET), body, &[])
+    }
+
+    /// GETs the given path
+    pub fn get(&self, path: &str) -> TestResponse {
+        self.request("GET", path, &[], &[])
+    }
+
+    /// performs a raw HTTP/1.1 request
+    pub fn request(&self, method: &str, path: &str, body: &[u8], headers: &[(&str, &str)]) -> TestResponse {
+        let mut stream = self.connect();
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nContent-Length: {}\r\nConnection: close\r\n", method, path, self.port, body.len());
+        for &(name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).and_then(|_| stream.write_all(body)).expect("Unable to send request");
+
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw).expect("Unable to read response");
+        let raw = String::from_utf8_lossy(&raw).into_owned();
+
+        let (head, body) = match raw.find("\r\n\r\n") {
+            Some(pos) => (&raw[..pos], &raw[pos + 4..]),
+            None => (&raw[..], ""),
+        };
+        let mut lines = head.split("\r\n");
+        let status = lines.next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|code| code.parse().ok())
+            .expect("Invalid response status line");
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.find(':').map(|pos| (line[..pos].trim().to_owned(), line[pos + 1..].trim().to_owned())))
+            .collect();
+
+        let chunked = headers.iter().any(|&(ref k, ref v)| k.eq_ignore_ascii_case("transfer-encoding") && v.eq_ignore_ascii_case("chunked"));
+        TestResponse {
+            status: status,
+            headers: headers,
+            body: if chunked { dechunk(body) } else { body.to_owned() },
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn write_file(path: &Path, content: &str) {
+    File::create(path).and_then(|mut f| f.write_all(content.as_bytes())).expect(&format!("Unable to write {:?}", path));
+}
+
+/// decodes a chunked transfer encoded body
+fn dechunk(mut raw: &str) -> String {
+    let mut body = String::new();
+    while let Some(pos) = raw.find("\r\n") {
+        let size = usize::from_str_radix(raw[..pos].split(';').next().unwrap_or("").trim(), 16).unwrap_or(0);
+        if size == 0 {
+            break;
+        }
+        body.push_str(&raw[pos + 2..pos + 2 + size]);
+        raw = &raw[pos + 2 + size + 2..];
+    }
+    body
+}
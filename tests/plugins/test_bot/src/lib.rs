@@ -0,0 +1,85 @@
+#![deny(warnings)]
+#![deny(missing_docs)]
+
+//! # test_bot
+//!
+//! Telegram bot used by webserver tests
+//!
+//! Echoes every text message back to its chat, replying via webhook to /webhook commands
+//! and sleeping 3 seconds before replying to /sleep commands
+
+extern crate client_lib;
+extern crate toml;
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use client_lib::{Bot, Telegram};
+use client_lib::entities::Request;
+
+use serde_json::value::Value as JsonValue;
+
+use toml::Value as TomlValue;
+
+struct TestBot {
+    api: Telegram,
+}
+
+impl Bot for TestBot {
+    fn new(api: Telegram, _config: &Arc<RwLock<TomlValue>>, _session: &Arc<RwLock<HashMap<String, JsonValue>>>) -> TestBot {
+        TestBot {
+            api: api,
+        }
+    }
+
+    fn parse_message(&self, request: &Request) -> Result<(String, Vec<String>), String> {
+        match request.get_message() {
+            &Some(ref msg) => match msg.get_text() {
+                &Some(ref text) => Ok((String::from("echo"), vec![msg.get_chat().get_id().to_string(), text.to_owned()])),
+                &None => Err(String::from("Unsupported message type")),
+            },
+            &None => Err(String::from("Empty message")),
+        }
+    }
+
+    fn dispatch(&self, method: &str, args: Vec<String>, _request: &Request) -> Result<JsonValue, String> {
+        match method {
+            "echo" => if args[1].starts_with("/sleep") {
+                thread::sleep(Duration::from_secs(3));
+                self.api.send_message(&args[0], &args[1], None, None, None, None)
+            }
+            else if args[1].starts_with("/webhook") {
+                self.api.via_webhook().send_message(&args[0], &args[1], None, None, None, None)
+            }
+            else {
+                self.api.send_message(&args[0], &args[1], None, None, None, None)
+            },
+            _ => Err(format!("Method {} not found", method)),
+        }
+    }
+}
+
+/// public C ABI to call the bot
+#[no_mangle]
+pub extern fn init_bot(ptr_config: *const Arc<RwLock<TomlValue>>, ptr_session: *const Arc<RwLock<HashMap<String, JsonValue>>>, secret: &str, ptr_request: *const &Request) -> *const Result<JsonValue, String> {
+    let config = unsafe {
+        assert!(!ptr_config.is_null());
+        &*ptr_config
+    };
+    let session = unsafe {
+        assert!(!ptr_session.is_null());
+        &*ptr_session
+    };
+    let request = unsafe {
+        assert!(!ptr_request.is_null());
+        &*ptr_request
+    };
+
+    Box::into_raw(Box::new(match Telegram::init_bot(TestBot::new, secret, &config, &session) {
+        Ok(bot) => bot.parse(request),
+        Err(e) => Err(format!("Error during bot init: {}", e)),
+    }))
+}
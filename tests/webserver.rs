@@ -0,0 +1,233 @@
+extern crate serde_json;
+
+mod common;
+
+use std::io::{Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::value::Value as JsonValue;
+
+use common::{TestServer, BOT, SECRET, fixture};
+
+#[test]
+fn get_replies_with_banner() {
+    let server = TestServer::start("", "");
+    let res = server.get("/");
+
+    assert_eq!(res.status, 200);
+    assert_eq!(res.body, "Rustegram server");
+}
+
+#[test]
+fn unknown_path_is_not_found() {
+    let server = TestServer::start("", "");
+
+    assert_eq!(server.post("/Telegram/", b"{}", &[]).status, 404);
+    assert_eq!(server.post(&format!("/Other/{}/prova", BOT), b"{}", &[]).status, 404);
+    assert!(server.telegram().get_calls().is_empty());
+}
+
+#[test]
+fn message_is_dispatched_to_plugin() {
+    let server = TestServer::start("", "");
+    let res = server.post_update(&fixture("message.json"));
+
+    assert_eq!(res.status, 200);
+    assert_eq!(serde_json::from_str::<JsonValue>(&res.body).unwrap()["ok"], JsonValue::Bool(true));
+    assert!(server.telegram().was_called("sendMessage", &[("chat_id", "1111111"), ("text", "/start hello")]));
+    assert_eq!(server.telegram().get_calls().len(), 1);
+}
+
+#[test]
+fn webhook_reply_is_written_in_response() {
+    let server = TestServer::start("", "");
+    let res = server.post_update(&fixture("webhook_message.json"));
+
+    assert_eq!(res.status, 200);
+    assert_eq!(res.header("Content-Type"), Some("application/json"));
+    let reply = serde_json::from_str::<JsonValue>(&res.body).unwrap();
+    assert_eq!(reply["method"], JsonValue::from("sendMessage"));
+    assert_eq!(reply["chat_id"], JsonValue::from("1111111"));
+    assert_eq!(reply["text"], JsonValue::from("/webhook hello"));
+    assert!(server.telegram().get_calls().is_empty());
+}
+
+#[test]
+fn unmanaged_update_is_an_error() {
+    let server = TestServer::start("", "");
+    let res = server.post_update(&fixture("edited_message.json"));
+
+    assert_eq!(res.status, 500);
+    assert_eq!(res.body, "Not managed");
+    assert!(server.telegram().get_calls().is_empty());
+}
+
+#[test]
+fn secret_mismatch_is_unauthorized() {
+    let server = TestServer::start("", "");
+    let res = server.post(&format!("/Telegram/{}/wrong", BOT), &fixture("message.json"), &[]);
+
+    assert_eq!(res.status, 401);
+    assert!(server.telegram().get_calls().is_empty());
+
+    //a forged request doesn't reserve the update_id of the genuine one
+    assert_eq!(server.post_update(&fixture("message.json")).status, 200);
+    assert_eq!(server.telegram().get_calls().len(), 1);
+}
+
+#[test]
+fn invalid_json_is_an_error() {
+    let server = TestServer::start("", "");
+    let res = server.post_update(b"not json");
+
+    assert_eq!(res.status, 500);
+    assert!(res.body.starts_with("Syntax error on json request"));
+}
+
+#[test]
+fn unknown_bot_is_not_found() {
+    let server = TestServer::start("", "");
+
+    assert_eq!(server.post("/Telegram/missing_bot/prova", &fixture("message.json"), &[]).status, 404);
+    assert_eq!(server.post("/Telegram/..%2Fbots%2Ftest_bot/prova", &fixture("message.json"), &[]).status, 404);
+}
+
+#[test]
+fn enabled_bots_are_taken_from_config() {
+    let server = TestServer::start("[bots]\nenabled = [\"missing_bot\"]\n", "");
+
+    //scanned bots aren't enabled anymore
+    assert_eq!(server.post_update(&fixture("message.json")).status, 404);
+
+    let res = server.post("/Telegram/missing_bot/prova", &fixture("message.json"), &[]);
+    assert_eq!(res.status, 500);
+    assert!(res.body.starts_with("Error loading plugin for missing_bot"));
+}
+
+#[test]
+fn oversized_body_is_refused() {
+    let server = TestServer::start(&format!("[bots]\nmax_body_size = 1048576\n\n[bots.{}]\nmax_body_size = 64\n", BOT), "");
+
+    assert_eq!(server.post_update(&fixture("message.json")).status, 413);
+    assert!(server.telegram().get_calls().is_empty());
+}
+
+#[test]
+fn secret_token_header_is_verified() {
+    let server = TestServer::start("[webhook.secret_tokens]\ntest_bot = \"header-token\"\n", "");
+    let path = format!("/Telegram/{}/{}", BOT, SECRET);
+
+    assert_eq!(server.post(&path, &fixture("message.json"), &[]).status, 401);
+    assert_eq!(server.post(&path, &fixture("message.json"), &[("X-Telegram-Bot-Api-Secret-Token", "wrong-token")]).status, 401);
+    assert!(server.telegram().get_calls().is_empty());
+
+    assert_eq!(server.post(&path, &fixture("message.json"), &[("X-Telegram-Bot-Api-Secret-Token", "header-token")]).status, 200);
+    assert_eq!(server.telegram().get_calls().len(), 1);
+}
+
+#[test]
+fn unknown_source_is_forbidden() {
+    let server = TestServer::start("[webhook]\nallowed_ips = [\"149.154.160.0/20\", \"91.108.4.0/22\"]\n", "");
+
+    assert_eq!(server.post_update(&fixture("message.json")).status, 403);
+    assert!(server.telegram().get_calls().is_empty());
+    //only webhooks are filtered
+    assert_eq!(server.get("/").status, 200);
+}
+
+#[test]
+fn slow_bot_times_out() {
+    let server = TestServer::start("[bots]\nexecution_timeout = 1\n", "");
+    let started = Instant::now();
+    let res = server.post_update(&fixture("sleep_message.json"));
+
+    assert_eq!(res.status, 504);
+    assert!(started.elapsed() < Duration::from_secs(3));
+    //other updates are still served meanwhile
+    assert_eq!(server.post_update(&fixture("message.json")).status, 200);
+}
+
+#[test]
+fn slow_body_times_out() {
+    let server = TestServer::start("[bots]\nread_timeout = 1\n", "");
+    let mut stream = server.connect();
+    write!(stream, "POST /Telegram/{}/{} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1000\r\nConnection: close\r\n\r\n{{\"update_id\":", BOT, SECRET).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 408"));
+    assert!(server.telegram().get_calls().is_empty());
+}
+
+#[test]
+fn chunked_oversized_body_is_refused() {
+    let server = TestServer::start("[bots]\nmax_body_size = 64\n", "");
+    let mut stream = server.connect();
+    write!(stream, "POST /Telegram/{}/{} HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n", BOT, SECRET).unwrap();
+    let body = fixture("message.json");
+    write!(stream, "{:x}\r\n", body.len()).unwrap();
+    stream.write_all(&body).unwrap();
+    write!(stream, "\r\n0\r\n\r\n").unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 413"));
+    assert!(server.telegram().get_calls().is_empty());
+}
+
+#[test]
+fn duplicate_update_is_acknowledged() {
+    let server = TestServer::start("", "");
+
+    assert_eq!(server.post_update(&fixture("message.json")).status, 200);
+    let res = server.post_update(&fixture("message.json"));
+    assert_eq!(res.status, 200);
+    assert_eq!(res.body, "");
+    assert_eq!(server.telegram().get_calls().len(), 1);
+
+    //failed updates are handled again
+    assert_eq!(server.post_update(&fixture("edited_message.json")).status, 500);
+    assert_eq!(server.post_update(&fixture("edited_message.json")).status, 500);
+}
+
+#[test]
+fn in_flight_update_is_acknowledged() {
+    let server = TestServer::start("[bots]\nexecution_timeout = 1\n", "");
+
+    assert_eq!(server.post_update(&fixture("sleep_message.json")).status, 504);
+    //the bot is still running, Telegram's retry must not start it again
+    assert_eq!(server.post_update(&fixture("sleep_message.json")).status, 200);
+    thread::sleep(Duration::from_secs(3));
+    assert_eq!(server.telegram().get_calls().len(), 1);
+}
+
+#[test]
+fn shutdown_drains_running_bots() {
+    let mut server = TestServer::start("", "");
+    let mut stream = server.connect();
+    let body = fixture("sleep_message.json");
+    write!(stream, "POST /Telegram/{}/{} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", BOT, SECRET, body.len()).unwrap();
+    stream.write_all(&body).unwrap();
+    //let the bot start before signaling
+    thread::sleep(Duration::from_millis(500));
+
+    let started = Instant::now();
+    assert!(server.terminate().success());
+    //the bot completed its run before the server exited
+    assert!(started.elapsed() >= Duration::from_secs(2));
+    assert_eq!(server.telegram().get_calls().len(), 1);
+    //in-memory sessions are snapshotted for next start
+    assert!(server.dir().join("tmp").join(format!("{}.session.json", BOT)).exists());
+}
+
+#[test]
+fn busy_bot_is_unavailable() {
+    let server = TestServer::start("[bots]\nexecution_timeout = 1\nmax_concurrency = 1\n", "");
+
+    assert_eq!(server.post_update(&fixture("sleep_message.json")).status, 504);
+    //the timed out bot still holds its only thread
+    assert_eq!(server.post_update(&fixture("message.json")).status, 503);
+    thread::sleep(Duration::from_secs(3));
+    assert_eq!(server.post_update(&fixture("message.json")).status, 200);
+}